@@ -0,0 +1,18 @@
+; ========================================================================
+; Minimal boot sector - loaded at 0000:7C00 with the boot drive in DL
+; ========================================================================
+
+bits 16
+org 0x7c00
+
+mov ax, 1
+mov bx, 2
+add ax, bx
+mov cl, dl
+
+hang:
+cmp ax, ax
+je hang
+
+times 510-($-$$) db 0
+dw 0xaa55
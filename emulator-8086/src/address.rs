@@ -0,0 +1,195 @@
+use std::fmt;
+
+use crate::{modrm::EffectiveAddress, reg::SegmentRegister};
+
+/// The 8086's 20 bit address space
+pub const MEMORY_SIZE: usize = 1 << 20;
+/// Last byte reachable with A20 enabled, FFFF:FFFF. The 64K - 16 bytes past 1 MiB are the high memory area
+pub const HMA_END: u32 = 0x10ffef;
+
+const ADDRESS_MASK: u32 = MEMORY_SIZE as u32 - 1;
+
+/// A segment:offset pair
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SegmentedAddress {
+    pub segment: u16,
+    pub offset: u16,
+}
+
+impl fmt::Display for SegmentedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.segment, self.offset)
+    }
+}
+
+impl SegmentedAddress {
+    pub fn new(segment: u16, offset: u16) -> Self {
+        Self { segment, offset }
+    }
+
+    /// Move along within the segment, the offset wraps at 64K rather than carrying into the segment
+    pub fn wrapping_add(&self, delta: u16) -> Self {
+        Self::new(self.segment, self.offset.wrapping_add(delta))
+    }
+
+    /// Physical address on the bus. A real 8086 only has 20 address lines so anything past 1 MiB wraps back
+    /// round to 0, with A20 enabled (like a 286 or later) it reaches into the HMA instead
+    pub fn physical(&self, a20_enabled: bool) -> u32 {
+        let linear = ((self.segment as u32) << 4) + self.offset as u32;
+        if a20_enabled {
+            linear
+        } else {
+            linear & ADDRESS_MASK
+        }
+    }
+}
+
+/// What a memory access is for, which decides the segment it goes through
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryAccess {
+    /// A mod r/m memory operand
+    Operand(EffectiveAddress),
+    /// A direct address outside of mod r/m, e.g. `mov al, [addr]` or `xlat`
+    Direct,
+    /// DS:SI for movs, lods, cmps and outs
+    StringSource,
+    /// ES:DI for movs, stos, cmps, scas and ins
+    StringDestination,
+    /// Push, pop, call and ret
+    Stack,
+    InstructionFetch,
+}
+
+impl MemoryAccess {
+    /// The segment used, taking a segment override prefix into account where one is allowed
+    pub fn segment(&self, segment_override: Option<SegmentRegister>) -> SegmentRegister {
+        let default = match self {
+            Self::Operand(ea) => ea.default_segment(),
+            Self::Direct | Self::StringSource => SegmentRegister::DS,
+            // these can't be overridden
+            Self::StringDestination => return SegmentRegister::ES,
+            Self::Stack => return SegmentRegister::SS,
+            Self::InstructionFetch => return SegmentRegister::CS,
+        };
+
+        segment_override.unwrap_or(default)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SegmentRegisters {
+    pub es: u16,
+    pub cs: u16,
+    pub ss: u16,
+    pub ds: u16,
+}
+
+impl SegmentRegisters {
+    pub fn get(&self, segment: SegmentRegister) -> u16 {
+        match segment {
+            SegmentRegister::ES => self.es,
+            SegmentRegister::CS => self.cs,
+            SegmentRegister::SS => self.ss,
+            SegmentRegister::DS => self.ds,
+        }
+    }
+
+    pub fn set(&mut self, segment: SegmentRegister, value: u16) {
+        match segment {
+            SegmentRegister::ES => self.es = value,
+            SegmentRegister::CS => self.cs = value,
+            SegmentRegister::SS => self.ss = value,
+            SegmentRegister::DS => self.ds = value,
+        }
+    }
+
+    /// Full address for an access at an offset
+    pub fn address(
+        &self,
+        access: MemoryAccess,
+        segment_override: Option<SegmentRegister>,
+        offset: u16,
+    ) -> SegmentedAddress {
+        SegmentedAddress::new(self.get(access.segment(segment_override)), offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        modrm::{parse_mod_rm, DisplacementValue, Rm},
+        reg::Register,
+    };
+
+    fn operand(modrm: u8) -> MemoryAccess {
+        match parse_mod_rm(modrm, true).unwrap().1 {
+            Rm::EffectiveAddressCalculation(ea, _) => MemoryAccess::Operand(ea),
+            Rm::Register(_) => panic!("not a memory operand"),
+        }
+    }
+
+    #[test]
+    fn test_default_segments() {
+        use SegmentRegister::*;
+
+        let segments = SegmentRegisters {
+            es: 0x1000,
+            cs: 0x2000,
+            ss: 0x3000,
+            ds: 0x4000,
+        };
+
+        // [bp + si], [bp + di] and [bp + disp] are SS, but mod 00 rm 110 is a direct address in DS
+        assert_eq!(operand(0b00_000_010).segment(None), SS);
+        assert_eq!(operand(0b00_000_011).segment(None), SS);
+        assert_eq!(operand(0b01_000_110).segment(None), SS);
+        assert_eq!(operand(0b00_000_110).segment(None), DS);
+        // [bx + si], [si], [di], [bx]
+        for rm in [0b000, 0b001, 0b100, 0b101, 0b111] {
+            assert_eq!(operand(rm).segment(None), DS);
+        }
+        assert_eq!(MemoryAccess::Direct.segment(None), DS);
+        assert_eq!(MemoryAccess::StringSource.segment(None), DS);
+        assert_eq!(MemoryAccess::StringDestination.segment(None), ES);
+        assert_eq!(MemoryAccess::Stack.segment(None), SS);
+        assert_eq!(MemoryAccess::InstructionFetch.segment(None), CS);
+
+        // overrides work on operands and the string source, the rest ignore them
+        assert_eq!(operand(0b00_000_010).segment(Some(ES)), ES);
+        assert_eq!(operand(0b00_000_111).segment(Some(CS)), CS);
+        assert_eq!(MemoryAccess::StringSource.segment(Some(SS)), SS);
+        assert_eq!(MemoryAccess::StringDestination.segment(Some(DS)), ES);
+        assert_eq!(MemoryAccess::Stack.segment(Some(DS)), SS);
+        assert_eq!(MemoryAccess::InstructionFetch.segment(Some(DS)), CS);
+
+        let address = segments.address(MemoryAccess::Stack, Some(DS), 0xfffe);
+        assert_eq!(address, SegmentedAddress::new(0x3000, 0xfffe));
+        assert_eq!(address.physical(false), 0x3fffe);
+    }
+
+    #[test]
+    fn test_wraparound() {
+        // [bp + di - 2] with bp + di = 1 wraps to the top of the segment
+        let ea = EffectiveAddress::DoubleReg(Register::BP, Register::DI);
+        let offset = ea.offset(&DisplacementValue::Byte(0xfe), |reg| match reg {
+            Register::BP => 0x0001,
+            _ => 0x0000,
+        });
+        assert_eq!(offset, 0xffff);
+
+        // the second byte of a word at offset ffff comes from offset 0 of the same segment
+        let address = SegmentedAddress::new(0x1234, 0xffff);
+        assert_eq!(address.wrapping_add(1), SegmentedAddress::new(0x1234, 0));
+        assert_eq!(address.wrapping_add(1).physical(false), 0x12340);
+
+        // FFFF:0010 is 0 on an 8086, the start of the HMA with A20 on
+        let address = SegmentedAddress::new(0xffff, 0x0010);
+        assert_eq!(address.physical(false), 0);
+        assert_eq!(address.physical(true), 0x100000);
+        assert_eq!(
+            SegmentedAddress::new(0xffff, 0xffff).physical(true),
+            HMA_END
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use crate::text;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Clocks in a bus cycle (T1-T4) before any wait states are added
+const BUS_CYCLE_CLOCKS: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuVariant {
+    I8086,
+    I8088,
+}
+
+impl CpuVariant {
+    /// Size of the prefetch queue in bytes
+    pub fn queue_size(&self) -> usize {
+        match self {
+            Self::I8086 => 6,
+            Self::I8088 => 4,
+        }
+    }
+
+    /// Bytes moved per bus cycle
+    pub fn bus_width(&self) -> usize {
+        match self {
+            Self::I8086 => 2,
+            Self::I8088 => 1,
+        }
+    }
+}
+
+impl FromStr for CpuVariant {
+    type Err = TraceError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "8086" => Ok(Self::I8086),
+            "8088" => Ok(Self::I8088),
+            _ => Err(TraceError::InvalidCpu(s.to_owned())),
+        }
+    }
+}
+
+/// What the EU needs to run a single instruction, this is what gets fed into the model
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InstructionTiming {
+    /// Length of the instruction in bytes, all of these have to come through the queue
+    pub length: u8,
+    /// Internal EU clocks once the instruction is decoded, not counting memory transfers
+    pub eu_clocks: u32,
+    /// Memory/IO bus cycles the EU needs - a word operand on the 8088 (or at an odd address on the 8086) is two
+    pub transfers: u32,
+    /// Set if the instruction transfers control - the queue is flushed and fetching restarts here
+    pub branch_target: Option<u16>,
+}
+
+/// What actually happened while running an instruction through the model
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InstructionReport {
+    /// Actual clocks from the end of the previous instruction to the end of this one
+    pub cycles: u32,
+    /// Queue fill when the EU started on this instruction
+    pub queue_before: usize,
+    /// Queue fill when the EU finished this instruction
+    pub queue_after: usize,
+    /// Prefetch bus cycles started by the BIU during this instruction
+    pub fetch_bus_cycles: u32,
+    /// Bus cycles run on behalf of the EU
+    pub eu_bus_cycles: u32,
+    /// Wait state clocks across all bus cycles started during this instruction
+    pub wait_state_clocks: u32,
+    /// Clocks the EU sat idle waiting on the queue or the bus
+    pub eu_stall_clocks: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BusCycleKind {
+    Fetch(usize),
+    Eu,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BusCycle {
+    kind: BusCycleKind,
+    remaining: u32,
+}
+
+/// Clock by clock model of the BIU prefetching into the queue while the EU executes
+#[derive(Debug)]
+pub struct BusInterfaceUnit {
+    variant: CpuVariant,
+    wait_states: u32,
+    queue: usize,
+    fetch_address: u16,
+    bus: Option<BusCycle>,
+    clock: u64,
+}
+
+impl BusInterfaceUnit {
+    pub fn new(variant: CpuVariant) -> Self {
+        Self {
+            variant,
+            wait_states: 0,
+            queue: 0,
+            fetch_address: 0,
+            bus: None,
+            clock: 0,
+        }
+    }
+
+    /// Wait states added to every bus cycle
+    pub fn with_wait_states(mut self, wait_states: u32) -> Self {
+        self.wait_states = wait_states;
+        self
+    }
+
+    /// Address prefetching starts from, this only matters for word alignment on the 8086
+    pub fn with_fetch_address(mut self, address: u16) -> Self {
+        self.fetch_address = address;
+        self
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue
+    }
+
+    /// Total clocks elapsed since the model was created
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// Run one instruction through the model
+    pub fn execute(&mut self, timing: &InstructionTiming) -> InstructionReport {
+        let start = self.clock;
+        let mut report = InstructionReport {
+            queue_before: self.queue,
+            ..Default::default()
+        };
+
+        // pull the instruction bytes out of the queue, waiting on the BIU for any that aren't there yet
+        let mut needed = timing.length as usize;
+        loop {
+            let taken = needed.min(self.queue);
+            self.queue -= taken;
+            needed -= taken;
+
+            if needed == 0 {
+                break;
+            }
+
+            self.tick(false, &mut report);
+            report.eu_stall_clocks += 1;
+        }
+
+        // the BIU is free to keep prefetching while the EU is busy
+        for _ in 0..timing.eu_clocks {
+            self.tick(false, &mut report);
+        }
+
+        // the EU has priority for the bus, but has to wait for a fetch that's already running to finish
+        for _ in 0..timing.transfers {
+            while self.bus.is_some() {
+                self.tick(true, &mut report);
+                report.eu_stall_clocks += 1;
+            }
+
+            self.start_bus_cycle(BusCycleKind::Eu, &mut report);
+            report.eu_bus_cycles += 1;
+
+            while self.bus.is_some() {
+                self.tick(true, &mut report);
+            }
+        }
+
+        // a fetch that's already on the bus runs to the end before the flush, then its bytes are thrown away too
+        if let Some(target) = timing.branch_target {
+            while self.bus.is_some() {
+                self.tick(true, &mut report);
+                report.eu_stall_clocks += 1;
+            }
+            self.queue = 0;
+            self.bus = None;
+            self.fetch_address = target;
+        }
+
+        report.queue_after = self.queue;
+        report.cycles = (self.clock - start) as u32;
+        report
+    }
+
+    /// Advance one clock, starting a prefetch if the bus is idle and the EU doesn't want it
+    fn tick(&mut self, eu_wants_bus: bool, report: &mut InstructionReport) {
+        if self.bus.is_none() && !eu_wants_bus {
+            // fetches from an odd address on the 8086 only bring in one byte
+            let bytes = if self.fetch_address % 2 == 1 {
+                1
+            } else {
+                self.variant.bus_width()
+            };
+
+            if self.variant.queue_size() - self.queue >= bytes {
+                self.start_bus_cycle(BusCycleKind::Fetch(bytes), report);
+                report.fetch_bus_cycles += 1;
+            }
+        }
+
+        self.clock += 1;
+
+        if let Some(cycle) = &mut self.bus {
+            cycle.remaining -= 1;
+
+            if cycle.remaining == 0 {
+                if let BusCycleKind::Fetch(bytes) = cycle.kind {
+                    self.queue += bytes;
+                    self.fetch_address = self.fetch_address.wrapping_add(bytes as u16);
+                }
+                self.bus = None;
+            }
+        }
+    }
+
+    fn start_bus_cycle(&mut self, kind: BusCycleKind, report: &mut InstructionReport) {
+        self.bus = Some(BusCycle {
+            kind,
+            remaining: BUS_CYCLE_CLOCKS + self.wait_states,
+        });
+        report.wait_state_clocks += self.wait_states;
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    InvalidCpu(String),
+    InvalidLine(usize, String),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCpu(cpu) => write!(f, "Invalid cpu '{}', expected 8086 or 8088", cpu),
+            Self::InvalidLine(line, contents) => {
+                write!(f, "Invalid trace line {}: '{}'", line, contents)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+/// An instruction in a trace along with the cycle count it's expected to take
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub timing: InstructionTiming,
+    pub expected_cycles: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceMismatch {
+    /// Index of the instruction in the trace
+    pub index: usize,
+    pub expected_cycles: u32,
+    pub actual_cycles: u32,
+}
+
+/// Recorded model timings, replayed to catch regressions in the model. These come from the model itself rather
+/// than real hardware, so they don't say anything about accuracy - checking the model against published hardware
+/// traces is still to do. The format is line based, `#` starts a comment:
+///
+/// ```text
+/// cpu 8088
+/// wait 0
+/// # length eu_clocks transfers branch_target expected_cycles
+/// 2 2 0 - 10
+/// 2 2 0 - 8
+/// ```
+#[derive(Debug)]
+pub struct TimingTrace {
+    variant: CpuVariant,
+    wait_states: u32,
+    entries: Vec<TraceEntry>,
+}
+
+impl TimingTrace {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    pub fn entries(&self) -> &[TraceEntry] {
+        &self.entries
+    }
+
+    /// Run the trace through a fresh model, returning every instruction that no longer takes the recorded cycles
+    pub fn regressions(&self) -> Vec<TraceMismatch> {
+        let mut biu = BusInterfaceUnit::new(self.variant).with_wait_states(self.wait_states);
+
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| {
+                let report = biu.execute(&entry.timing);
+                (report.cycles != entry.expected_cycles).then_some(TraceMismatch {
+                    index,
+                    expected_cycles: entry.expected_cycles,
+                    actual_cycles: report.cycles,
+                })
+            })
+            .collect()
+    }
+}
+
+impl FromStr for TimingTrace {
+    type Err = TraceError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut variant = CpuVariant::I8086;
+        let mut wait_states = 0;
+        let mut entries = Vec::new();
+
+        for (line_number, raw_line, line) in text::lines(s) {
+            let invalid = || TraceError::InvalidLine(line_number, raw_line.to_owned());
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            match fields.as_slice() {
+                ["cpu", cpu] => variant = cpu.parse()?,
+                ["wait", count] => wait_states = count.parse().map_err(|_| invalid())?,
+                [length, eu_clocks, transfers, branch, expected] => {
+                    let branch_target = match *branch {
+                        "-" => None,
+                        target => Some(text::parse_number(target).ok_or_else(invalid)?),
+                    };
+
+                    entries.push(TraceEntry {
+                        timing: InstructionTiming {
+                            length: length.parse().map_err(|_| invalid())?,
+                            eu_clocks: eu_clocks.parse().map_err(|_| invalid())?,
+                            transfers: transfers.parse().map_err(|_| invalid())?,
+                            branch_target,
+                        },
+                        expected_cycles: expected.parse().map_err(|_| invalid())?,
+                    });
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Self {
+            variant,
+            wait_states,
+            entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_8088_fetch_bound() {
+        // mov reg, reg - 2 bytes, 2 clocks, but the 8088 can only fetch a byte every 4 clocks
+        let mov = InstructionTiming {
+            length: 2,
+            eu_clocks: 2,
+            transfers: 0,
+            branch_target: None,
+        };
+        let mut biu = BusInterfaceUnit::new(CpuVariant::I8088);
+
+        assert_eq!(biu.execute(&mov).cycles, 10);
+        for _ in 0..8 {
+            assert_eq!(biu.execute(&mov).cycles, 8);
+        }
+    }
+
+    #[test]
+    fn test_timing_regressions() -> Result<()> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("timing-regressions");
+
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let trace = TimingTrace::load(&path)?;
+
+            assert!(!trace.entries().is_empty(), "{:?} is empty", path);
+            assert_eq!(trace.regressions(), vec![], "{:?} has changed", path);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+use crate::{
+    disk::{DiskImage, SECTOR_SIZE},
+    memory::MemoryMap,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Physical address the BIOS loads the boot sector to (0000:7C00)
+pub const BOOT_ADDRESS: usize = 0x7c00;
+/// Last two bytes of a bootable sector
+pub const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// Drive numbers as passed in DL - 0x00 is the first floppy, 0x80 the first hard disk
+pub const FIRST_FLOPPY: u8 = 0x00;
+pub const FIRST_HARD_DISK: u8 = 0x80;
+
+#[derive(Debug)]
+pub enum BootError {
+    MissingSignature([u8; 2]),
+    NoRam,
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingSignature(sig) => write!(
+                f,
+                "Boot sector ends in {:02x} {:02x}, expected 55 aa",
+                sig[0], sig[1]
+            ),
+            Self::NoRam => write!(f, "No RAM at 0000:7c00 to load the boot sector into"),
+        }
+    }
+}
+
+impl std::error::Error for BootError {}
+
+/// Register state the BIOS hands over to a boot sector
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootState {
+    pub cs: u16,
+    pub ip: u16,
+    /// Drive the sector was loaded from
+    pub dl: u8,
+    pub ds: u16,
+    pub es: u16,
+    pub ss: u16,
+    /// Real BIOSes differ here, we put the stack just under the boot sector
+    pub sp: u16,
+}
+
+/// Loads sector 0 of a disk image the way the BIOS bootstrap (INT 19h) does
+#[derive(Debug)]
+pub struct BootLoader {
+    drive: u8,
+    check_signature: bool,
+}
+
+impl Default for BootLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BootLoader {
+    pub fn new() -> Self {
+        Self {
+            drive: FIRST_FLOPPY,
+            check_signature: true,
+        }
+    }
+
+    pub fn with_drive(mut self, drive: u8) -> Self {
+        self.drive = drive;
+        self
+    }
+
+    /// Early PC BIOSes didn't check for 55 aa, so this can be turned off
+    pub fn with_check_signature(mut self, check_signature: bool) -> Self {
+        self.check_signature = check_signature;
+        self
+    }
+
+    /// Copy the first sector (C/H/S 0/0/1) to 0000:7C00
+    pub fn load(&self, memory: &mut MemoryMap, disk: &DiskImage) -> Result<BootState> {
+        let sector = disk.read_sectors(0, 0, 1, 1)?;
+
+        let signature = [sector[SECTOR_SIZE - 2], sector[SECTOR_SIZE - 1]];
+        if self.check_signature && signature != BOOT_SIGNATURE {
+            return Err(Box::new(BootError::MissingSignature(signature)));
+        }
+
+        if memory.ram_available(BOOT_ADDRESS as u32) < SECTOR_SIZE {
+            return Err(Box::new(BootError::NoRam));
+        }
+        memory.write_bytes(BOOT_ADDRESS as u32, sector);
+
+        Ok(BootState {
+            cs: 0,
+            ip: BOOT_ADDRESS as u16,
+            dl: self.drive,
+            ds: 0,
+            es: 0,
+            ss: 0,
+            sp: BOOT_ADDRESS as u16,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{disassembler::Disassembler, disk::DiskGeometry};
+
+    #[test]
+    fn test_boot_fixture() -> Result<()> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("asm/boot");
+        let disk = DiskImage::from_bytes(std::fs::read(path)?, Some(DiskGeometry::FLOPPY_360K))?;
+
+        let mut memory = MemoryMap::new();
+        assert!(BootLoader::new().load(&mut memory, &disk).is_err());
+        memory.map_ram(0x00000..=0x9ffff)?;
+        let state = BootLoader::new().load(&mut memory, &disk)?;
+
+        assert_eq!((state.cs, state.ip, state.dl), (0, 0x7c00, FIRST_FLOPPY));
+        assert_eq!(memory.read_bytes(0x7dfe, 2), BOOT_SIGNATURE);
+
+        let code = memory.read_bytes(BOOT_ADDRESS as u32, 14);
+        let listing = Disassembler::new(&code).with_origin(0x7c00).decode()?;
+        assert_eq!(
+            listing,
+            "bits 16\norg 0x7c00\n\nmov ax, 1\nmov bx, 2\nadd ax, bx\nmov cl, dl\ncmp ax, ax\nje -4"
+        );
+        Ok(())
+    }
+}
@@ -1,12 +1,17 @@
 use std::{
-    io::{Cursor, Read, Seek},
+    collections::{HashSet, VecDeque},
+    io::{Cursor, Read},
     str::FromStr,
 };
 
 use crate::opcodes::{NextFieldType, OpcodeContext, OpcodeMnemonic};
 use crate::operation::Operation;
 use crate::{
+    address::SegmentedAddress,
+    biu::CpuVariant,
+    memory::MemoryMap,
     modrm::{parse_mod_reg_rm, parse_mod_rm, DisplacementLen, DisplacementValue, Rm},
+    mz::Relocation,
     operation::Operand,
 };
 use log::{debug, info};
@@ -16,22 +21,126 @@ type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 // type IsWord = bool;
 // type IsSigned = bool;
 
+/// Where the decoder gets instruction bytes from
+pub trait CodeSource {
+    /// Next byte of the instruction stream, None when there's nothing left
+    fn next_byte(&mut self) -> Result<Option<u8>>;
+}
+
+impl CodeSource for Cursor<Vec<u8>> {
+    fn next_byte(&mut self) -> Result<Option<u8>> {
+        let mut next = [0u8; 1];
+        let read_len = self.read(&mut next)?;
+
+        if read_len == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(next[0]))
+    }
+}
+
+/// Fetches from emulated memory at CS:IP as it decodes, so code that patches itself is seen straight away unless
+/// the prefetch queue is on. IP wraps within the code segment and there's no end to the stream, so decode one
+/// instruction at a time
 #[derive(Debug)]
-pub struct Disassembler {
-    instructions_bin: Cursor<Vec<u8>>,
+pub struct MemoryCode<'a> {
+    memory: &'a mut MemoryMap,
+    ip: SegmentedAddress,
+    /// Where the BIU fetches from next, IP plus whatever is queued
+    fetch: SegmentedAddress,
+    queue: VecDeque<u8>,
+    queue_size: usize,
 }
 
-impl Disassembler {
-    pub fn new(instructions: &[u8]) -> Self {
+impl<'a> MemoryCode<'a> {
+    pub fn new(memory: &'a mut MemoryMap, ip: SegmentedAddress) -> Self {
         Self {
-            instructions_bin: Cursor::new(instructions.to_vec()),
+            memory,
+            ip,
+            fetch: ip,
+            queue: VecDeque::new(),
+            queue_size: 0,
+        }
+    }
+
+    /// Keep the queue topped up ahead of IP like the real BIU does, so writes to code that has already been
+    /// fetched aren't seen until a jump flushes it. Some programs use this to tell an 8088 from an 8086.
+    ///
+    /// This is only an approximation. The queue is refilled straight after every byte is taken, with no bus timing,
+    /// so the stale window is always the full 6 or 4 bytes. A real queue is often part empty when the EU is busy
+    /// on the bus, and `biu::BusInterfaceUnit` models that. The refill also reads through `read_byte_at`, so a
+    /// memory mapped device with read side effects is read ahead of IP, even if that code never runs
+    pub fn with_prefetch_queue(mut self, variant: CpuVariant) -> Self {
+        self.queue_size = variant.queue_size();
+        self
+    }
+
+    /// Throw away the queue and carry on fetching from `ip`, as a jump, call or interrupt does
+    pub fn flush(&mut self, ip: SegmentedAddress) {
+        self.queue.clear();
+        self.ip = ip;
+        self.fetch = ip;
+    }
+
+    /// Address of the next byte to be fetched, i.e. past the last decoded instruction
+    pub fn ip(&self) -> SegmentedAddress {
+        self.ip
+    }
+
+    /// For writing to memory between instructions, e.g. to patch code ahead of IP
+    pub fn memory_mut(&mut self) -> &mut MemoryMap {
+        self.memory
+    }
+}
+
+impl CodeSource for MemoryCode<'_> {
+    fn next_byte(&mut self) -> Result<Option<u8>> {
+        let byte = match self.queue.pop_front() {
+            Some(byte) => byte,
+            None => {
+                let byte = self.memory.read_byte_at(self.fetch);
+                self.fetch = self.fetch.wrapping_add(1);
+                byte
+            }
+        };
+        self.ip = self.ip.wrapping_add(1);
+
+        while self.queue.len() < self.queue_size {
+            self.queue.push_back(self.memory.read_byte_at(self.fetch));
+            self.fetch = self.fetch.wrapping_add(1);
         }
+
+        Ok(Some(byte))
     }
+}
 
-    /// Main loop
+#[derive(Debug)]
+pub struct Disassembler<S = Cursor<Vec<u8>>> {
+    instructions_bin: S,
+    /// Byte read ahead by `peek`, handed back on the next read
+    peeked: Option<u8>,
+    /// Bytes taken from the source so far
+    position: usize,
+    /// Offsets of segment words the loader fixes up, see `with_relocations`
+    relocations: HashSet<usize>,
+    origin: u16,
+}
+
+impl Disassembler {
+    pub fn new(instructions: &[u8]) -> Self {
+        Self::from_source(Cursor::new(instructions.to_vec()))
+    }
+
+    /// Main loop, runs to the end of the buffer. Sources without an end like `MemoryCode` go through
+    /// `decode_next_op` instead
     pub fn decode(&mut self) -> Result<String> {
         let mut decoded = String::from_str("bits 16\n")?;
 
+        if self.origin != 0 {
+            decoded.push_str(&format!("org 0x{:x}\n", self.origin));
+        }
+
         while let Some(statement) = self.decode_next_op()? {
             decoded.push('\n');
 
@@ -42,19 +151,55 @@ impl Disassembler {
 
         Ok(decoded)
     }
+}
+
+impl<S: CodeSource> Disassembler<S> {
+    pub fn from_source(source: S) -> Self {
+        Self {
+            instructions_bin: source,
+            peeked: None,
+            position: 0,
+            relocations: HashSet::new(),
+            origin: 0,
+        }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.instructions_bin
+    }
+
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.instructions_bin
+    }
+
+    /// Offset the first instruction is loaded at, e.g. 0x100 for a .COM file
+    pub fn with_origin(mut self, origin: u16) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// MZ relocations for the image being decoded, far pointers with a relocated segment are shown symbolically
+    pub fn with_relocations(mut self, relocations: &[Relocation]) -> Self {
+        self.relocations = relocations.iter().map(Relocation::image_offset).collect();
+        self
+    }
 
     /// Read next byte - returns None if no more instructions
     fn read_next(&mut self) -> Result<Option<u8>> {
-        let mut next = [0u8; 1];
-        let read_len = self.instructions_bin.read(&mut next)?;
-
-        if read_len == 0 {
-            return Ok(None);
-        }
+        let next = match self.peeked.take() {
+            Some(peeked) => peeked,
+            None => match self.instructions_bin.next_byte()? {
+                Some(next) => {
+                    self.position += 1;
+                    next
+                }
+                None => return Ok(None),
+            },
+        };
 
-        debug!("read {:08b}", next[0]);
+        debug!("read {:08b}", next);
 
-        Ok(Some(next[0]))
+        Ok(Some(next))
     }
 
     /// Read expecting to panic if we can't read the next byte
@@ -78,10 +223,10 @@ impl Disassembler {
         })
     }
 
-    /// Peeks the next byte, but returns the offset to where it originally was
+    /// Peeks the next byte, the next read gets it again
     fn peek(&mut self) -> Result<u8> {
         let val = self.read_expecting()?;
-        self.instructions_bin.seek_relative(-1)?;
+        self.peeked = Some(val);
         Ok(val)
     }
 
@@ -96,7 +241,8 @@ impl Disassembler {
         })
     }
 
-    fn decode_next_op(&mut self) -> Result<Option<Operation>> {
+    /// Decode a single instruction - returns None if no more instructions
+    pub fn decode_next_op(&mut self) -> Result<Option<Operation>> {
         let opcode_byte = self.read_next()?;
 
         match opcode_byte {
@@ -207,6 +353,21 @@ impl Disassembler {
                             None,
                         )))
                     }
+                    NextFieldType::FarPointer => {
+                        let offset = self.read_word()?;
+                        let relocated = self.relocations.contains(&self.position);
+                        let segment = self.read_word()?;
+
+                        Ok(Some(Operation::new(
+                            *opcode_ctx.mnemonic(),
+                            Operand::FarPointer {
+                                segment,
+                                offset,
+                                relocated,
+                            },
+                            None,
+                        )))
+                    }
                     _ => todo!(),
                 }
             }
@@ -237,4 +398,48 @@ mod test {
         assert_eq!(expected.to_string(), "mov cx, bx".to_owned());
         Ok(())
     }
+
+    #[test]
+    fn test_com_origin() -> Result<()> {
+        let instructions: [u8; 2] = [0b10001001, 0b11011001];
+        let mut d = Disassembler::new(&instructions).with_origin(0x100);
+
+        assert_eq!(d.decode()?, "bits 16\norg 0x100\n\nmov cx, bx");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_from_memory() -> Result<()> {
+        let mut memory = MemoryMap::new();
+        memory.map_ram(0x00000..=0x9ffff)?;
+
+        // mov cx, bx with the mod r/m byte wrapping round to the start of the segment, then mov cx, bx again
+        let start = SegmentedAddress::new(0x1000, 0xffff);
+        memory.write_word_at(start, 0b11011001_10001001);
+        memory.write_word_at(start.wrapping_add(2), 0b11011001_10001001);
+
+        let mut d = Disassembler::from_source(MemoryCode::new(&mut memory, start));
+        assert_eq!(d.decode_next_op()?.unwrap().to_string(), "mov cx, bx");
+        assert_eq!(d.source().ip(), SegmentedAddress::new(0x1000, 0x0001));
+
+        // patching the next instruction's mod r/m byte is seen straight away by the same decoder
+        d.source_mut().memory_mut().write_byte(0x10002, 0b11010011);
+        assert_eq!(d.decode_next_op()?.unwrap().to_string(), "mov bx, dx");
+
+        // with the 8086's 6 byte queue the patched copy is already queued, the old one runs until a flush
+        let start = SegmentedAddress::new(0x2000, 0);
+        for offset in (0..6).step_by(2) {
+            memory.write_word_at(start.wrapping_add(offset), 0b11011001_10001001);
+        }
+        let code = MemoryCode::new(&mut memory, start).with_prefetch_queue(CpuVariant::I8086);
+        let mut d = Disassembler::from_source(code);
+        assert_eq!(d.decode_next_op()?.unwrap().to_string(), "mov cx, bx");
+        d.source_mut().memory_mut().write_byte(0x20003, 0b11010011);
+        d.source_mut().memory_mut().write_byte(0x20005, 0b11010011);
+        assert_eq!(d.decode_next_op()?.unwrap().to_string(), "mov cx, bx");
+        let ip = d.source().ip();
+        d.source_mut().flush(ip);
+        assert_eq!(d.decode_next_op()?.unwrap().to_string(), "mov bx, dx");
+        Ok(())
+    }
 }
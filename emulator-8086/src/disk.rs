@@ -0,0 +1,268 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiskGeometry {
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors_per_track: u8,
+}
+
+impl DiskGeometry {
+    pub const FLOPPY_360K: Self = Self::new(40, 2, 9);
+    pub const FLOPPY_720K: Self = Self::new(80, 2, 9);
+    pub const FLOPPY_1_2M: Self = Self::new(80, 2, 15);
+    pub const FLOPPY_1_44M: Self = Self::new(80, 2, 18);
+
+    pub const fn new(cylinders: u16, heads: u8, sectors_per_track: u8) -> Self {
+        Self {
+            cylinders,
+            heads,
+            sectors_per_track,
+        }
+    }
+
+    /// Guess the geometry from the image size - standard floppy sizes are recognised, anything else is treated as
+    /// a hard disk with 16 heads and 63 sectors per track
+    pub fn from_image_size(len: usize) -> Option<Self> {
+        let known = [
+            Self::FLOPPY_360K,
+            Self::FLOPPY_720K,
+            Self::FLOPPY_1_2M,
+            Self::FLOPPY_1_44M,
+        ];
+        if let Some(geometry) = known.into_iter().find(|g| g.size() == len) {
+            return Some(geometry);
+        }
+
+        let track = 16 * 63 * SECTOR_SIZE;
+        if len == 0 || !len.is_multiple_of(track) || len / track > 1024 {
+            return None;
+        }
+        Some(Self::new((len / track) as u16, 16, 63))
+    }
+
+    pub fn total_sectors(&self) -> usize {
+        self.cylinders as usize * self.heads as usize * self.sectors_per_track as usize
+    }
+
+    /// Size of the image in bytes
+    pub fn size(&self) -> usize {
+        self.total_sectors() * SECTOR_SIZE
+    }
+
+    /// CHS to LBA, sectors are numbered from 1
+    pub fn lba(&self, cylinder: u16, head: u8, sector: u8) -> Option<usize> {
+        if cylinder >= self.cylinders
+            || head >= self.heads
+            || sector == 0
+            || sector > self.sectors_per_track
+        {
+            return None;
+        }
+
+        let track = cylinder as usize * self.heads as usize + head as usize;
+        Some(track * self.sectors_per_track as usize + sector as usize - 1)
+    }
+}
+
+impl fmt::Display for DiskGeometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}",
+            self.cylinders, self.heads, self.sectors_per_track
+        )
+    }
+}
+
+/// INT 13h status codes, these go back to the caller in AH
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskStatus {
+    Ok = 0x00,
+    InvalidCommand = 0x01,
+    AddressMarkNotFound = 0x02,
+    WriteProtected = 0x03,
+    SectorNotFound = 0x04,
+}
+
+impl fmt::Display for DiskStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Ok => "ok",
+            Self::InvalidCommand => "invalid command",
+            Self::AddressMarkNotFound => "address mark not found",
+            Self::WriteProtected => "write protected",
+            Self::SectorNotFound => "sector not found",
+        };
+        write!(f, "{} (0x{:02x})", s, *self as u8)
+    }
+}
+
+impl std::error::Error for DiskStatus {}
+
+/// A raw disk image backing the INT 13h services, kept in memory and written back on `flush`
+#[derive(Debug)]
+pub struct DiskImage {
+    path: Option<PathBuf>,
+    data: Vec<u8>,
+    geometry: DiskGeometry,
+    read_only: bool,
+    dirty: bool,
+}
+
+impl DiskImage {
+    /// Open an image file, guessing the geometry from its size if one isn't given
+    pub fn open<P: AsRef<Path>>(path: P, geometry: Option<DiskGeometry>) -> Result<Self> {
+        let data = std::fs::read(&path)?;
+        let mut image = Self::from_bytes(data, geometry)?;
+        image.path = Some(path.as_ref().to_path_buf());
+        Ok(image)
+    }
+
+    /// An image that only lives in memory. Short images are padded out to the full geometry
+    pub fn from_bytes(mut data: Vec<u8>, geometry: Option<DiskGeometry>) -> Result<Self> {
+        let geometry = match geometry {
+            Some(geometry) => geometry,
+            None => DiskGeometry::from_image_size(data.len()).ok_or_else(|| {
+                format!(
+                    "Can't guess disk geometry for a {} byte image, set it explicitly",
+                    data.len()
+                )
+            })?,
+        };
+
+        if data.len() > geometry.size() {
+            return Err(format!(
+                "{} byte image is bigger than geometry {} allows",
+                data.len(),
+                geometry
+            )
+            .into());
+        }
+        data.resize(geometry.size(), 0);
+
+        Ok(Self {
+            path: None,
+            data,
+            geometry,
+            read_only: false,
+            dirty: false,
+        })
+    }
+
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn geometry(&self) -> &DiskGeometry {
+        &self.geometry
+    }
+
+    /// AH=02h, read `count` sectors starting at the given CHS address
+    pub fn read_sectors(
+        &self,
+        cylinder: u16,
+        head: u8,
+        sector: u8,
+        count: u8,
+    ) -> std::result::Result<&[u8], DiskStatus> {
+        let range = self.sector_range(cylinder, head, sector, count)?;
+        Ok(&self.data[range])
+    }
+
+    /// AH=03h, write whole sectors starting at the given CHS address
+    pub fn write_sectors(
+        &mut self,
+        cylinder: u16,
+        head: u8,
+        sector: u8,
+        data: &[u8],
+    ) -> std::result::Result<(), DiskStatus> {
+        if self.read_only {
+            return Err(DiskStatus::WriteProtected);
+        }
+        if !data.len().is_multiple_of(SECTOR_SIZE) || data.len() / SECTOR_SIZE > u8::MAX as usize {
+            return Err(DiskStatus::InvalidCommand);
+        }
+
+        let range = self.sector_range(cylinder, head, sector, (data.len() / SECTOR_SIZE) as u8)?;
+        self.data[range].copy_from_slice(data);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Write any changes back to the image file
+    pub fn flush(&mut self) -> Result<()> {
+        if let (true, Some(path)) = (self.dirty, &self.path) {
+            std::fs::write(path, &self.data)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn sector_range(
+        &self,
+        cylinder: u16,
+        head: u8,
+        sector: u8,
+        count: u8,
+    ) -> std::result::Result<std::ops::Range<usize>, DiskStatus> {
+        if count == 0 {
+            return Err(DiskStatus::InvalidCommand);
+        }
+
+        let lba = self
+            .geometry
+            .lba(cylinder, head, sector)
+            .ok_or(DiskStatus::SectorNotFound)?;
+        let start = lba * SECTOR_SIZE;
+        let end = start + count as usize * SECTOR_SIZE;
+
+        if end > self.data.len() {
+            return Err(DiskStatus::SectorNotFound);
+        }
+        Ok(start..end)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chs_read_write() -> Result<()> {
+        let mut disk = DiskImage::from_bytes(vec![0; 1474560], None)?;
+        assert_eq!(disk.geometry(), &DiskGeometry::FLOPPY_1_44M);
+
+        // cylinder 1, head 1, sector 1 is the 55th sector on the disk (2 heads * 18 sectors + 18)
+        let sector = [0x5au8; SECTOR_SIZE];
+        disk.write_sectors(1, 1, 1, &sector)?;
+        assert_eq!(disk.read_sectors(1, 1, 1, 1)?, &sector);
+        assert_eq!(disk.read_sectors(0, 0, 1, 1)?, &[0u8; SECTOR_SIZE]);
+        assert_eq!(disk.geometry().lba(1, 1, 1), Some(54));
+
+        assert_eq!(
+            disk.read_sectors(0, 0, 0, 1),
+            Err(DiskStatus::SectorNotFound)
+        );
+        assert_eq!(
+            disk.read_sectors(80, 0, 1, 1),
+            Err(DiskStatus::SectorNotFound)
+        );
+
+        let mut disk = disk.with_read_only(true);
+        assert_eq!(
+            disk.write_sectors(0, 0, 1, &sector),
+            Err(DiskStatus::WriteProtected)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+use std::fmt;
+
+use crate::memory::MemoryMap;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Size of the program segment prefix, a .COM image starts right after it at offset 0x100
+pub const PSP_SIZE: usize = 0x100;
+/// Segment just past conventional memory (640K)
+pub const DEFAULT_MEMORY_TOP: u16 = 0xa000;
+/// Segment DOS would normally hand out to the first program
+pub const DEFAULT_LOAD_SEGMENT: u16 = 0x0100;
+
+/// Longest command tail that fits in the PSP, leaving room for the terminating CR
+const MAX_COMMAND_TAIL: usize = 126;
+
+/// Where the CP/M style CALL 5 lands, in the reserved part of the PSP
+const CALL5_STUB_OFFSET: usize = 0x3c;
+/// Offset of the far call at PSP:0005. It doubles as the CP/M "bytes available in the segment" word at PSP:0006,
+/// DOS uses FEF0 but the low nibble has to match the stub's offset
+const CALL5_TARGET_OFFSET: u16 = 0xfefc;
+
+/// CALL 5 dispatcher - swaps the far return to PSP:000A for one straight back to the program's near call, then
+/// does the CP/M call as int 21h with the function from CL
+const CALL5_STUB: [u8; 15] = [
+    0x58, // pop ax - return offset into the PSP, not needed
+    0x58, // pop ax - PSP segment
+    0x55, // push bp
+    0x89, 0xe5, // mov bp, sp
+    0x87, 0x46, 0x02, // xchg ax, [bp + 2] - swap the segment for the program's return address
+    0x5d, // pop bp
+    0x50, // push ax
+    0x88, 0xcc, // mov ah, cl
+    0xcd, 0x21, // int 21h
+    0xcb, // retf
+];
+
+#[derive(Debug)]
+pub enum LoaderError {
+    CommandTailTooLong(usize),
+    ImageTooLarge(usize),
+    OutOfMemory,
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CommandTailTooLong(len) => write!(
+                f,
+                "Command tail is {} bytes, max is {}",
+                len, MAX_COMMAND_TAIL
+            ),
+            Self::ImageTooLarge(len) => write!(f, "Program image of {} bytes is too large", len),
+            Self::OutOfMemory => write!(f, "Not enough memory to load program"),
+        }
+    }
+}
+
+impl std::error::Error for LoaderError {}
+
+/// Program segment prefix, the 256 byte header DOS puts in front of every program it loads
+#[derive(Debug)]
+pub struct Psp {
+    memory_top: u16,
+    parent: u16,
+    environment: u16,
+    command_tail: Vec<u8>,
+}
+
+impl Psp {
+    /// `memory_top` is the segment of the first byte past the memory allocated to the program
+    pub fn new(memory_top: u16) -> Self {
+        Self {
+            memory_top,
+            parent: 0,
+            environment: 0,
+            command_tail: Vec::new(),
+        }
+    }
+
+    /// Everything after the program name, as DOS would store it - usually including the leading space
+    pub fn with_command_tail(mut self, tail: &str) -> Result<Self> {
+        if tail.len() > MAX_COMMAND_TAIL {
+            return Err(Box::new(LoaderError::CommandTailTooLong(tail.len())));
+        }
+        self.command_tail = tail.as_bytes().to_vec();
+        Ok(self)
+    }
+
+    pub fn with_parent(mut self, parent: u16) -> Self {
+        self.parent = parent;
+        self
+    }
+
+    pub fn with_environment(mut self, environment: u16) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    /// Lay out the PSP, `vectors` are the current int 22h/23h/24h vectors as stored in the IVT
+    pub fn to_bytes(&self, segment: u16, vectors: [u8; 12]) -> [u8; PSP_SIZE] {
+        let mut psp = [0u8; PSP_SIZE];
+
+        // int 20h, so a program can terminate by jumping (or returning) to offset 0
+        psp[0x00..0x02].copy_from_slice(&[0xcd, 0x20]);
+        psp[0x02..0x04].copy_from_slice(&self.memory_top.to_le_bytes());
+        // CP/M style far call to the dispatcher. The segment is picked so the call lands on the stub in this PSP,
+        // which like real DOS relies on the address wrapping at 1 MiB for PSPs below segment 0fech
+        let call5_segment =
+            segment.wrapping_sub((CALL5_TARGET_OFFSET as usize - CALL5_STUB_OFFSET) as u16 >> 4);
+        psp[0x05] = 0x9a;
+        psp[0x06..0x08].copy_from_slice(&CALL5_TARGET_OFFSET.to_le_bytes());
+        psp[0x08..0x0a].copy_from_slice(&call5_segment.to_le_bytes());
+        // saved terminate, ctrl-break and critical error vectors
+        psp[0x0a..0x16].copy_from_slice(&vectors);
+        psp[0x16..0x18].copy_from_slice(&self.parent.to_le_bytes());
+
+        // job file table - stdin, stdout, stderr on con, stdaux on aux, stdprn on prn, the rest unused
+        psp[0x18..0x2c].fill(0xff);
+        psp[0x18..0x1d].copy_from_slice(&[0x01, 0x01, 0x01, 0x00, 0x02]);
+        psp[0x2c..0x2e].copy_from_slice(&self.environment.to_le_bytes());
+        psp[0x32..0x34].copy_from_slice(&20u16.to_le_bytes());
+        psp[0x34..0x36].copy_from_slice(&0x18u16.to_le_bytes());
+        psp[0x36..0x38].copy_from_slice(&segment.to_le_bytes());
+        psp[CALL5_STUB_OFFSET..CALL5_STUB_OFFSET + CALL5_STUB.len()].copy_from_slice(&CALL5_STUB);
+
+        // int 21h; retf
+        psp[0x50..0x53].copy_from_slice(&[0xcd, 0x21, 0xcb]);
+
+        // unopened FCBs - default drive and blank file names
+        psp[0x5d..0x68].fill(b' ');
+        psp[0x6d..0x78].fill(b' ');
+
+        // command tail, the length doesn't count the CR on the end
+        let tail_len = self.command_tail.len();
+        psp[0x80] = tail_len as u8;
+        psp[0x81..0x81 + tail_len].copy_from_slice(&self.command_tail);
+        psp[0x81 + tail_len] = b'\r';
+
+        psp
+    }
+}
+
+/// Register state DOS hands to a freshly loaded program. AX and the general registers are left at 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadedProgram {
+    pub psp_segment: u16,
+    pub cs: u16,
+    pub ip: u16,
+    pub ss: u16,
+    pub sp: u16,
+    pub ds: u16,
+    pub es: u16,
+}
+
+/// Loads a .COM image into RAM the same way DOS does
+#[derive(Debug)]
+pub struct ComLoader {
+    segment: u16,
+    memory_top: u16,
+    command_tail: String,
+}
+
+impl Default for ComLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ComLoader {
+    pub fn new() -> Self {
+        Self {
+            segment: DEFAULT_LOAD_SEGMENT,
+            memory_top: DEFAULT_MEMORY_TOP,
+            command_tail: String::new(),
+        }
+    }
+
+    /// Segment the PSP goes at, the program itself starts at offset 0x100 in it
+    pub fn with_segment(mut self, segment: u16) -> Self {
+        self.segment = segment;
+        self
+    }
+
+    pub fn with_memory_top(mut self, memory_top: u16) -> Self {
+        self.memory_top = memory_top;
+        self
+    }
+
+    pub fn with_command_tail(mut self, tail: &str) -> Self {
+        self.command_tail = tail.to_owned();
+        self
+    }
+
+    /// Write the PSP and image into memory. CS, DS, ES and SS all point at the PSP, IP is 0x100 and a 0 word is
+    /// pushed so a near `ret` lands on the `int 20h` at PSP:0000
+    pub fn load(&self, memory: &mut MemoryMap, image: &[u8]) -> Result<LoadedProgram> {
+        // the program, the PSP and the initial stack word all have to fit in one 64K segment
+        if image.len() > 0x10000 - PSP_SIZE - 2 {
+            return Err(Box::new(LoaderError::ImageTooLarge(image.len())));
+        }
+
+        let base = (self.segment as u32) << 4;
+        let available = ((self.memory_top as u32) << 4)
+            .saturating_sub(base)
+            .min(0x10000) as usize;
+        let available = available.min(memory.ram_available(base));
+
+        if available < PSP_SIZE + image.len() + 2 {
+            return Err(Box::new(LoaderError::OutOfMemory));
+        }
+
+        write_psp(memory, self.segment, self.memory_top, &self.command_tail)?;
+        memory.write_bytes(base + PSP_SIZE as u32, image);
+
+        // stack starts at the top of the segment (or of RAM, if that's lower) with a 0 return address on it
+        let sp = (available - 2) as u16;
+        memory.write_bytes(base + sp as u32, &[0, 0]);
+
+        Ok(LoadedProgram {
+            psp_segment: self.segment,
+            cs: self.segment,
+            ip: PSP_SIZE as u16,
+            ss: self.segment,
+            sp,
+            ds: self.segment,
+            es: self.segment,
+        })
+    }
+}
+
+/// Build a PSP at `segment`, picking up the terminate/ctrl-break/critical error vectors from the IVT in memory
+pub(crate) fn write_psp(
+    memory: &mut MemoryMap,
+    segment: u16,
+    memory_top: u16,
+    command_tail: &str,
+) -> Result<()> {
+    let base = (segment as u32) << 4;
+    if memory.ram_available(base) < PSP_SIZE {
+        return Err(Box::new(LoaderError::OutOfMemory));
+    }
+
+    let mut vectors = [0u8; 12];
+    vectors.copy_from_slice(&memory.read_bytes(0x22 * 4, 12));
+
+    let psp = Psp::new(memory_top).with_command_tail(command_tail)?;
+    memory.write_bytes(base, &psp.to_bytes(segment, vectors));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::address::SegmentedAddress;
+
+    #[test]
+    fn test_load_com() -> Result<()> {
+        let mut memory = MemoryMap::new();
+        memory.map_ram(0x00000..=0x9ffff)?;
+        memory.write_bytes(0x1fffe, &[0xaa, 0xaa]);
+        let image = [0xb4, 0x4c, 0xcd, 0x21];
+
+        let loaded = ComLoader::new()
+            .with_segment(0x1000)
+            .with_command_tail(" foo.txt")
+            .load(&mut memory, &image)?;
+
+        assert_eq!(loaded.cs, 0x1000);
+        assert_eq!(loaded.ip, 0x100);
+        assert_eq!(loaded.sp, 0xfffe);
+
+        let psp = memory.read_bytes(0x10000, PSP_SIZE);
+        assert_eq!(&psp[0..2], &[0xcd, 0x20]);
+        assert_eq!(&psp[2..4], &DEFAULT_MEMORY_TOP.to_le_bytes());
+        assert_eq!(psp[0x80], 8);
+        assert_eq!(&psp[0x81..0x8a], b" foo.txt\r");
+        assert_eq!(memory.read_bytes(0x10100, image.len()), image);
+        assert_eq!(memory.read_bytes(0x1fffe, 2), [0, 0]);
+
+        // CALL 5 wraps round to the dispatcher stub in the PSP
+        let call5 = SegmentedAddress::new(u16::from_le_bytes([psp[8], psp[9]]), 0xfefc);
+        assert_eq!(psp[5], 0x9a);
+        assert_eq!(call5.physical(false), 0x10000 + CALL5_STUB_OFFSET as u32);
+        assert_eq!(memory.read_bytes(0x1003c, CALL5_STUB.len()), CALL5_STUB);
+
+        // the PSP has to land in RAM, so does the stack
+        assert!(write_psp(&mut memory, 0xa000, DEFAULT_MEMORY_TOP, "").is_err());
+        let loaded = ComLoader::new()
+            .with_segment(0x9800)
+            .with_memory_top(0xb000)
+            .load(&mut memory, &image)?;
+        assert_eq!(loaded.sp, 0x7ffe);
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use std::{cell::RefCell, fmt, ops::RangeInclusive, rc::Rc};
+
+use log::warn;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Value read back from a port nothing is listening on - the data bus floats high
+pub const UNCLAIMED_PORT_VALUE: u8 = 0xff;
+
+/// Anything that sits on the I/O bus. Devices get the full port number, so one device can claim several ports
+pub trait IoDevice {
+    fn read_port(&mut self, port: u16) -> u8;
+
+    fn write_port(&mut self, port: u16, value: u8);
+
+    /// Called as the CPU runs, with the number of CPU clocks since the last call
+    fn tick(&mut self, _cycles: u32) {}
+}
+
+/// Lets a device be shared, e.g. when another device needs to poke at it directly
+impl<T: IoDevice> IoDevice for Rc<RefCell<T>> {
+    fn read_port(&mut self, port: u16) -> u8 {
+        self.borrow_mut().read_port(port)
+    }
+
+    fn write_port(&mut self, port: u16, value: u8) {
+        self.borrow_mut().write_port(port, value)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.borrow_mut().tick(cycles)
+    }
+}
+
+#[derive(Debug)]
+pub enum IoBusError {
+    PortConflict(u16),
+}
+
+impl fmt::Display for IoBusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PortConflict(port) => write!(f, "Port 0x{:04x} is already claimed", port),
+        }
+    }
+}
+
+impl std::error::Error for IoBusError {}
+
+/// Routes `in`/`out` to whichever device claimed the port
+#[derive(Default)]
+pub struct IoBus {
+    devices: Vec<Box<dyn IoDevice>>,
+    /// Port ranges and the index of the device that claimed them
+    ports: Vec<(RangeInclusive<u16>, usize)>,
+}
+
+impl fmt::Debug for IoBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IoBus")
+            .field("devices", &self.devices.len())
+            .field("ports", &self.ports)
+            .finish()
+    }
+}
+
+impl IoBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attach a device to one or more port ranges, fails if any of them are already taken
+    pub fn register(
+        &mut self,
+        ranges: &[RangeInclusive<u16>],
+        device: Box<dyn IoDevice>,
+    ) -> Result<()> {
+        for (i, range) in ranges.iter().enumerate() {
+            let conflict = self
+                .ports
+                .iter()
+                .map(|(claimed, _)| claimed)
+                .chain(&ranges[..i])
+                .find(|claimed| claimed.start() <= range.end() && range.start() <= claimed.end());
+
+            if let Some(claimed) = conflict {
+                let port = *claimed.start().max(range.start());
+                return Err(Box::new(IoBusError::PortConflict(port)));
+            }
+        }
+
+        let index = self.devices.len();
+        self.devices.push(device);
+        self.ports
+            .extend(ranges.iter().map(|range| (range.clone(), index)));
+        Ok(())
+    }
+
+    /// `in al, dx`
+    pub fn read_byte(&mut self, port: u16) -> u8 {
+        match self.device_for(port) {
+            Some(device) => device.read_port(port),
+            None => {
+                warn!("read from unclaimed port 0x{:04x}", port);
+                UNCLAIMED_PORT_VALUE
+            }
+        }
+    }
+
+    /// `out dx, al`
+    pub fn write_byte(&mut self, port: u16, value: u8) {
+        match self.device_for(port) {
+            Some(device) => device.write_port(port, value),
+            None => warn!("write of 0x{:02x} to unclaimed port 0x{:04x}", value, port),
+        }
+    }
+
+    /// `in ax, dx` - goes out as two byte accesses, low port first
+    pub fn read_word(&mut self, port: u16) -> u16 {
+        let low = self.read_byte(port) as u16;
+        let high = (self.read_byte(port.wrapping_add(1)) as u16) << 8;
+        low + high
+    }
+
+    /// `out dx, ax`
+    pub fn write_word(&mut self, port: u16, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write_byte(port, low);
+        self.write_byte(port.wrapping_add(1), high);
+    }
+
+    /// Let every device know how many CPU clocks have gone by
+    pub fn tick(&mut self, cycles: u32) {
+        for device in self.devices.iter_mut() {
+            device.tick(cycles);
+        }
+    }
+
+    fn device_for(&mut self, port: u16) -> Option<&mut Box<dyn IoDevice>> {
+        let (_, index) = self.ports.iter().find(|(range, _)| range.contains(&port))?;
+        self.devices.get_mut(*index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Latches whatever's written to it and counts clocks
+    #[derive(Default)]
+    struct Latch {
+        value: u8,
+        cycles: u32,
+    }
+
+    impl IoDevice for Latch {
+        fn read_port(&mut self, port: u16) -> u8 {
+            self.value.wrapping_add(port as u8)
+        }
+
+        fn write_port(&mut self, _port: u16, value: u8) {
+            self.value = value;
+        }
+
+        fn tick(&mut self, cycles: u32) {
+            self.cycles += cycles;
+        }
+    }
+
+    #[test]
+    fn test_io_bus() -> Result<()> {
+        let latch = Rc::new(RefCell::new(Latch::default()));
+        let mut bus = IoBus::new();
+        bus.register(&[0x300..=0x301, 0x310..=0x310], Box::new(latch.clone()))?;
+
+        assert!(bus
+            .register(&[0x301..=0x302], Box::new(Latch::default()))
+            .is_err());
+
+        bus.write_byte(0x310, 0x40);
+        assert_eq!(bus.read_byte(0x300), 0x40);
+        assert_eq!(bus.read_word(0x300), 0x4140);
+        assert_eq!(bus.read_byte(0x302), UNCLAIMED_PORT_VALUE);
+
+        bus.tick(4);
+        bus.tick(3);
+        assert_eq!(latch.borrow().cycles, 7);
+        Ok(())
+    }
+}
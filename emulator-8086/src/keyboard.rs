@@ -0,0 +1,647 @@
+use std::{collections::VecDeque, fmt, ops::RangeInclusive, path::Path, str::FromStr};
+
+use log::{debug, warn};
+
+use crate::{io::IoDevice, pic::IrqLine, text};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Data port at 0x60 and status/command at 0x64. 0x61 in between belongs to the speaker
+pub const KEYBOARD_PORTS: [RangeInclusive<u16>; 2] = [0x60..=0x60, 0x64..=0x64];
+pub const KEYBOARD_IRQ: u8 = 1;
+
+/// Cycles between key events from a script unless it says otherwise, about 4ms on a PC
+pub const DEFAULT_KEY_DELAY: u64 = 20_000;
+
+// keyboard replies
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const SELF_TEST_PASSED: u8 = 0xaa;
+const ECHO: u8 = 0xee;
+
+// controller status register
+const STATUS_OUTPUT_FULL: u8 = 0b0000_0001;
+const STATUS_SYSTEM_FLAG: u8 = 0b0000_0100;
+const STATUS_LAST_WRITE_COMMAND: u8 = 0b0000_1000;
+const STATUS_NOT_INHIBITED: u8 = 0b0001_0000;
+
+// controller command byte
+const COMMAND_IRQ1_ENABLE: u8 = 0b0000_0001;
+const COMMAND_SYSTEM_FLAG: u8 = 0b0000_0100;
+const COMMAND_KEYBOARD_DISABLE: u8 = 0b0001_0000;
+const COMMAND_TRANSLATE: u8 = 0b0100_0000;
+
+// controller output port
+const OUTPUT_PORT_A20: u8 = 0b0000_0010;
+
+/// A physical key, with its make code in scancode sets 1 and 2
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key {
+    set1: u8,
+    set2: u8,
+    /// Extended keys send an E0 prefix in both sets
+    extended: bool,
+}
+
+/// Name, set 1 code, set 2 code and the characters it types unshifted and shifted, if any
+const KEYS: &[(&str, u8, u8, &str)] = &[
+    ("esc", 0x01, 0x76, ""),
+    ("1", 0x02, 0x16, "1!"),
+    ("2", 0x03, 0x1e, "2@"),
+    ("3", 0x04, 0x26, "3#"),
+    ("4", 0x05, 0x25, "4$"),
+    ("5", 0x06, 0x2e, "5%"),
+    ("6", 0x07, 0x36, "6^"),
+    ("7", 0x08, 0x3d, "7&"),
+    ("8", 0x09, 0x3e, "8*"),
+    ("9", 0x0a, 0x46, "9("),
+    ("0", 0x0b, 0x45, "0)"),
+    ("minus", 0x0c, 0x4e, "-_"),
+    ("equals", 0x0d, 0x55, "=+"),
+    ("backspace", 0x0e, 0x66, ""),
+    ("tab", 0x0f, 0x0d, "\t\t"),
+    ("q", 0x10, 0x15, "qQ"),
+    ("w", 0x11, 0x1d, "wW"),
+    ("e", 0x12, 0x24, "eE"),
+    ("r", 0x13, 0x2d, "rR"),
+    ("t", 0x14, 0x2c, "tT"),
+    ("y", 0x15, 0x35, "yY"),
+    ("u", 0x16, 0x3c, "uU"),
+    ("i", 0x17, 0x43, "iI"),
+    ("o", 0x18, 0x44, "oO"),
+    ("p", 0x19, 0x4d, "pP"),
+    ("lbracket", 0x1a, 0x54, "[{"),
+    ("rbracket", 0x1b, 0x5b, "]}"),
+    ("enter", 0x1c, 0x5a, "\n\n"),
+    ("lctrl", 0x1d, 0x14, ""),
+    ("a", 0x1e, 0x1c, "aA"),
+    ("s", 0x1f, 0x1b, "sS"),
+    ("d", 0x20, 0x23, "dD"),
+    ("f", 0x21, 0x2b, "fF"),
+    ("g", 0x22, 0x34, "gG"),
+    ("h", 0x23, 0x33, "hH"),
+    ("j", 0x24, 0x3b, "jJ"),
+    ("k", 0x25, 0x42, "kK"),
+    ("l", 0x26, 0x4b, "lL"),
+    ("semicolon", 0x27, 0x4c, ";:"),
+    ("quote", 0x28, 0x52, "'\""),
+    ("backtick", 0x29, 0x0e, "`~"),
+    ("lshift", 0x2a, 0x12, ""),
+    ("backslash", 0x2b, 0x5d, "\\|"),
+    ("z", 0x2c, 0x1a, "zZ"),
+    ("x", 0x2d, 0x22, "xX"),
+    ("c", 0x2e, 0x21, "cC"),
+    ("v", 0x2f, 0x2a, "vV"),
+    ("b", 0x30, 0x32, "bB"),
+    ("n", 0x31, 0x31, "nN"),
+    ("m", 0x32, 0x3a, "mM"),
+    ("comma", 0x33, 0x41, ",<"),
+    ("period", 0x34, 0x49, ".>"),
+    ("slash", 0x35, 0x4a, "/?"),
+    ("rshift", 0x36, 0x59, ""),
+    ("kpmultiply", 0x37, 0x7c, ""),
+    ("lalt", 0x38, 0x11, ""),
+    ("space", 0x39, 0x29, "  "),
+    ("capslock", 0x3a, 0x58, ""),
+    ("f1", 0x3b, 0x05, ""),
+    ("f2", 0x3c, 0x06, ""),
+    ("f3", 0x3d, 0x04, ""),
+    ("f4", 0x3e, 0x0c, ""),
+    ("f5", 0x3f, 0x03, ""),
+    ("f6", 0x40, 0x0b, ""),
+    ("f7", 0x41, 0x83, ""),
+    ("f8", 0x42, 0x0a, ""),
+    ("f9", 0x43, 0x01, ""),
+    ("f10", 0x44, 0x09, ""),
+    ("numlock", 0x45, 0x77, ""),
+    ("scrolllock", 0x46, 0x7e, ""),
+    ("kp7", 0x47, 0x6c, ""),
+    ("kp8", 0x48, 0x75, ""),
+    ("kp9", 0x49, 0x7d, ""),
+    ("kpminus", 0x4a, 0x7b, ""),
+    ("kp4", 0x4b, 0x6b, ""),
+    ("kp5", 0x4c, 0x73, ""),
+    ("kp6", 0x4d, 0x74, ""),
+    ("kpplus", 0x4e, 0x79, ""),
+    ("kp1", 0x4f, 0x69, ""),
+    ("kp2", 0x50, 0x72, ""),
+    ("kp3", 0x51, 0x7a, ""),
+    ("kp0", 0x52, 0x70, ""),
+    ("kpperiod", 0x53, 0x71, ""),
+    ("f11", 0x57, 0x78, ""),
+    ("f12", 0x58, 0x07, ""),
+];
+
+/// Keys that send an E0 prefix, mostly the ones added with the 101-key layout
+const EXTENDED_KEYS: &[(&str, u8, u8)] = &[
+    ("kpenter", 0x1c, 0x5a),
+    ("rctrl", 0x1d, 0x14),
+    ("kpdivide", 0x35, 0x4a),
+    ("ralt", 0x38, 0x11),
+    ("home", 0x47, 0x6c),
+    ("up", 0x48, 0x75),
+    ("pgup", 0x49, 0x7d),
+    ("left", 0x4b, 0x6b),
+    ("right", 0x4d, 0x74),
+    ("end", 0x4f, 0x69),
+    ("down", 0x50, 0x72),
+    ("pgdn", 0x51, 0x7a),
+    ("insert", 0x52, 0x70),
+    ("delete", 0x53, 0x71),
+];
+
+impl Key {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+
+        KEYS.iter()
+            .find(|(key, ..)| *key == name)
+            .map(|(_, set1, set2, _)| Self {
+                set1: *set1,
+                set2: *set2,
+                extended: false,
+            })
+            .or_else(|| {
+                EXTENDED_KEYS
+                    .iter()
+                    .find(|(key, ..)| *key == name)
+                    .map(|(_, set1, set2)| Self {
+                        set1: *set1,
+                        set2: *set2,
+                        extended: true,
+                    })
+            })
+    }
+
+    /// The key that types a character on a US layout, and whether shift needs to be held for it
+    pub fn from_char(c: char) -> Option<(Self, bool)> {
+        KEYS.iter().find_map(|(_, set1, set2, chars)| {
+            let shifted = chars.chars().position(|typed| typed == c)? == 1;
+            let key = Self {
+                set1: *set1,
+                set2: *set2,
+                extended: false,
+            };
+            Some((key, shifted))
+        })
+    }
+
+    /// Bytes the keyboard sends for this key going down or up, in scancode set 1 or 2
+    pub fn scancodes(&self, set: u8, pressed: bool) -> Vec<u8> {
+        let mut codes = Vec::with_capacity(3);
+        if self.extended {
+            codes.push(0xe0);
+        }
+
+        match (set, pressed) {
+            (1, true) => codes.push(self.set1),
+            (1, false) => codes.push(self.set1 | 0x80),
+            (_, true) => codes.push(self.set2),
+            (_, false) => codes.extend([0xf0, self.set2]),
+        }
+
+        codes
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyScriptError {
+    InvalidLine(usize, String),
+    UnknownKey(usize, String),
+}
+
+impl fmt::Display for KeyScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLine(line, contents) => {
+                write!(f, "Invalid script line {}: '{}'", line, contents)
+            }
+            Self::UnknownKey(line, key) => write!(f, "Unknown key '{}' on line {}", key, line),
+        }
+    }
+}
+
+impl std::error::Error for KeyScriptError {}
+
+/// A key going down or up at a point in emulated time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// CPU cycles since the script started
+    pub at: u64,
+    pub key: Key,
+    pub pressed: bool,
+}
+
+/// Keystrokes to play back into the keyboard controller, so interactive programs can be run headless.
+/// One command per line, `#` starts a comment except after `type`, which types the rest of the line as it is,
+/// `#` and trailing spaces included:
+///
+/// ```text
+/// wait 2000000   # cycles to wait before the next key
+/// delay 10000    # cycles between key events from here on
+/// type dir /w
+/// press enter    # down then up
+/// down lctrl
+/// press c
+/// up lctrl
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyScript {
+    events: Vec<KeyEvent>,
+}
+
+impl KeyScript {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+}
+
+impl FromStr for KeyScript {
+    type Err = KeyScriptError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut events = Vec::new();
+        let mut at = 0;
+        let mut delay = DEFAULT_KEY_DELAY;
+
+        let mut push = |at: &mut u64, delay: u64, key: Key, pressed: bool| {
+            events.push(KeyEvent {
+                at: *at,
+                key,
+                pressed,
+            });
+            *at += delay;
+        };
+
+        for (line_number, raw_line, line) in text::lines(s) {
+            let invalid = || KeyScriptError::InvalidLine(line_number, raw_line.to_owned());
+            let (command, argument) = match raw_line.trim_start().split_once(char::is_whitespace) {
+                Some(("type", text)) => ("type", text),
+                _ => line.split_once(char::is_whitespace).ok_or_else(invalid)?,
+            };
+            let find_key = |name: &str| {
+                Key::from_name(name)
+                    .ok_or_else(|| KeyScriptError::UnknownKey(line_number, name.into()))
+            };
+
+            match command {
+                "wait" => at += argument.trim().parse::<u64>().map_err(|_| invalid())?,
+                "delay" => delay = argument.trim().parse().map_err(|_| invalid())?,
+                "press" => {
+                    let key = find_key(argument.trim())?;
+                    push(&mut at, delay, key, true);
+                    push(&mut at, delay, key, false);
+                }
+                "down" => push(&mut at, delay, find_key(argument.trim())?, true),
+                "up" => push(&mut at, delay, find_key(argument.trim())?, false),
+                "type" => {
+                    let shift = find_key("lshift")?;
+                    // straight from the raw line, only the one separating space is dropped
+                    for c in argument.chars() {
+                        let (key, shifted) = Key::from_char(c)
+                            .ok_or_else(|| KeyScriptError::UnknownKey(line_number, c.into()))?;
+                        if shifted {
+                            push(&mut at, delay, shift, true);
+                        }
+                        push(&mut at, delay, key, true);
+                        push(&mut at, delay, key, false);
+                        if shifted {
+                            push(&mut at, delay, shift, false);
+                        }
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Self { events })
+    }
+}
+
+/// Two byte commands waiting on their data byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Pending {
+    None,
+    /// Controller command expecting a byte written to 0x60
+    Controller(u8),
+    /// Keyboard command expecting its argument
+    Keyboard(u8),
+}
+
+/// 8042 keyboard controller with an AT keyboard behind it. Keys come in through `press`/`release` or a
+/// `KeyScript` played back as the CPU runs
+#[derive(Debug)]
+pub struct KeyboardController {
+    irq: Option<IrqLine>,
+    command_byte: u8,
+    output_port: u8,
+    /// What the next read of 0x60 returns
+    output: Option<u8>,
+    last_write_command: bool,
+    pending: Pending,
+    /// Bytes the keyboard has sent that the controller hasn't taken yet
+    queue: VecDeque<u8>,
+    scancode_set: u8,
+    scanning: bool,
+    script: VecDeque<KeyEvent>,
+    cycles: u64,
+}
+
+impl Default for KeyboardController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyboardController {
+    /// Set up the way the BIOS leaves it - IRQ1 on, keyboard in set 2 and the controller translating to set 1
+    pub fn new() -> Self {
+        Self {
+            irq: None,
+            command_byte: COMMAND_IRQ1_ENABLE | COMMAND_SYSTEM_FLAG | COMMAND_TRANSLATE,
+            output_port: 0b0000_0001,
+            output: None,
+            last_write_command: false,
+            pending: Pending::None,
+            queue: VecDeque::new(),
+            scancode_set: 2,
+            scanning: true,
+            script: VecDeque::new(),
+            cycles: 0,
+        }
+    }
+
+    pub fn with_irq(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    pub fn with_script(mut self, script: KeyScript) -> Self {
+        self.script = script.events.into();
+        self
+    }
+
+    /// Whether the A20 line is let through, set by programs through the controller's output port
+    pub fn a20_enabled(&self) -> bool {
+        self.output_port & OUTPUT_PORT_A20 != 0
+    }
+
+    /// Nothing left in the script and nothing waiting to be read
+    pub fn is_idle(&self) -> bool {
+        self.script.is_empty() && self.queue.is_empty() && self.output.is_none()
+    }
+
+    pub fn press(&mut self, key: Key) {
+        self.key_event(key, true);
+    }
+
+    pub fn release(&mut self, key: Key) {
+        self.key_event(key, false);
+    }
+
+    fn key_event(&mut self, key: Key, pressed: bool) {
+        if !self.scanning {
+            debug!("keyboard is disabled, dropping {:?}", key);
+            return;
+        }
+
+        // translation turns set 2 into what an XT keyboard would have sent
+        let set = if self.command_byte & COMMAND_TRANSLATE != 0 {
+            1
+        } else {
+            self.scancode_set
+        };
+        self.queue.extend(key.scancodes(set, pressed));
+        self.fill_output();
+    }
+
+    /// Move the next byte from the keyboard into the output buffer if the program's read the last one
+    fn fill_output(&mut self) {
+        if self.output.is_none() && self.command_byte & COMMAND_KEYBOARD_DISABLE == 0 {
+            self.output = self.queue.pop_front();
+        }
+        self.update_irq();
+    }
+
+    fn update_irq(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.output.is_some() && self.command_byte & COMMAND_IRQ1_ENABLE != 0);
+        }
+    }
+
+    /// The controller's own replies jump ahead of anything from the keyboard
+    fn controller_reply(&mut self, value: u8) {
+        if let Some(waiting) = self.output.replace(value) {
+            self.queue.push_front(waiting);
+        }
+        self.update_irq();
+    }
+
+    fn keyboard_reply(&mut self, replies: &[u8]) {
+        self.queue.extend(replies);
+        self.fill_output();
+    }
+
+    fn write_controller_command(&mut self, command: u8) {
+        match command {
+            0x20 => self.controller_reply(self.command_byte),
+            0x60 | 0xd1 => self.pending = Pending::Controller(command),
+            // self test and keyboard interface test
+            0xaa => self.controller_reply(0x55),
+            0xab => self.controller_reply(0x00),
+            0xad => self.command_byte |= COMMAND_KEYBOARD_DISABLE,
+            0xae => {
+                self.command_byte &= !COMMAND_KEYBOARD_DISABLE;
+                self.fill_output();
+            }
+            // input port, nothing interesting on it
+            0xc0 => self.controller_reply(0x00),
+            0xd0 => self.controller_reply(self.output_port),
+            0xdd => self.output_port &= !OUTPUT_PORT_A20,
+            0xdf => self.output_port |= OUTPUT_PORT_A20,
+            // TODO: pulsing the reset line should reset the CPU
+            0xf0..=0xff if command & 0b0001 == 0 => {
+                warn!("keyboard controller asked to reset the cpu")
+            }
+            0xf0..=0xff => (),
+            _ => warn!("unsupported keyboard controller command 0x{:02x}", command),
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        match std::mem::replace(&mut self.pending, Pending::None) {
+            Pending::Controller(0x60) => {
+                self.command_byte = value;
+                self.fill_output();
+            }
+            Pending::Controller(_) => self.output_port = value,
+            Pending::Keyboard(command) => self.write_keyboard_argument(command, value),
+            Pending::None => self.write_keyboard_command(value),
+        }
+    }
+
+    fn write_keyboard_command(&mut self, command: u8) {
+        match command {
+            // LEDs, typematic rate and scancode set all take an argument
+            0xed | 0xf3 | 0xf0 => {
+                self.pending = Pending::Keyboard(command);
+                self.keyboard_reply(&[ACK]);
+            }
+            0xee => self.keyboard_reply(&[ECHO]),
+            0xf2 => self.keyboard_reply(&[ACK, 0xab, 0x83]),
+            0xf4 => {
+                self.scanning = true;
+                self.keyboard_reply(&[ACK]);
+            }
+            0xf5 | 0xf6 => {
+                self.scanning = command == 0xf6;
+                self.scancode_set = 2;
+                self.keyboard_reply(&[ACK]);
+            }
+            0xff => {
+                self.scanning = true;
+                self.scancode_set = 2;
+                self.queue.clear();
+                self.keyboard_reply(&[ACK, SELF_TEST_PASSED]);
+            }
+            _ => {
+                warn!("unsupported keyboard command 0x{:02x}", command);
+                self.keyboard_reply(&[RESEND]);
+            }
+        }
+    }
+
+    fn write_keyboard_argument(&mut self, command: u8, value: u8) {
+        match (command, value) {
+            // asking for the current set
+            (0xf0, 0) => self.keyboard_reply(&[ACK, self.scancode_set]),
+            (0xf0, 1 | 2) => {
+                self.scancode_set = value;
+                self.keyboard_reply(&[ACK]);
+            }
+            // TODO: set 3
+            (0xf0, _) => self.keyboard_reply(&[RESEND]),
+            _ => self.keyboard_reply(&[ACK]),
+        }
+    }
+}
+
+impl IoDevice for KeyboardController {
+    fn read_port(&mut self, port: u16) -> u8 {
+        if port == 0x64 {
+            let mut status = STATUS_NOT_INHIBITED;
+            if self.output.is_some() {
+                status |= STATUS_OUTPUT_FULL;
+            }
+            if self.command_byte & COMMAND_SYSTEM_FLAG != 0 {
+                status |= STATUS_SYSTEM_FLAG;
+            }
+            if self.last_write_command {
+                status |= STATUS_LAST_WRITE_COMMAND;
+            }
+            return status;
+        }
+
+        // the next byte turns up on the following tick, giving the handler a chance to EOI first
+        let value = self.output.take().unwrap_or_default();
+        self.update_irq();
+        value
+    }
+
+    fn write_port(&mut self, port: u16, value: u8) {
+        self.last_write_command = port == 0x64;
+        if port == 0x64 {
+            self.pending = Pending::None;
+            self.write_controller_command(value);
+        } else {
+            self.write_data(value);
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+
+        while let Some(event) = self.script.front().copied() {
+            if event.at > self.cycles {
+                break;
+            }
+            self.script.pop_front();
+            self.key_event(event.key, event.pressed);
+        }
+
+        self.fill_output();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pic::Pic;
+
+    #[test]
+    fn test_scripted_keys() -> Result<()> {
+        let script: KeyScript = "
+            # wait for the prompt
+            wait 1000
+            delay 100
+            type A
+            down lctrl
+            press c
+            up lctrl
+        "
+        .parse()?;
+        assert_eq!(script.events().len(), 8);
+        assert!("press nokey".parse::<KeyScript>().is_err());
+        // any whitespace can separate the command, and only that one character is dropped
+        let typed: KeyScript = "type\u{a0} a".parse()?;
+        assert_eq!(typed.events().len(), 4);
+        // '#' is shift+3 rather than a comment, and the trailing space is typed too
+        let typed: KeyScript = "type a#b ".parse()?;
+        assert_eq!(typed.events().len(), 10);
+        assert_eq!(typed.events()[3].key, Key::from_char('#').unwrap().0);
+        assert!("type #".parse::<KeyScript>().is_ok());
+
+        let pic = Pic::new().shared();
+        let mut keyboard = KeyboardController::new()
+            .with_irq(IrqLine::new(pic.clone(), KEYBOARD_IRQ))
+            .with_script(script);
+
+        keyboard.tick(999);
+        assert_eq!(keyboard.read_port(0x64) & STATUS_OUTPUT_FULL, 0);
+
+        // translated to set 1: shift, a, a break, shift break
+        let mut codes = Vec::new();
+        for _ in 0..4 {
+            keyboard.tick(100);
+            assert_eq!(pic.borrow_mut().acknowledge(), Some(0x09));
+            pic.borrow_mut().write_port(0x20, 0x20);
+            codes.push(keyboard.read_port(0x60));
+        }
+        assert_eq!(codes, [0x2a, 0x1e, 0x9e, 0xaa]);
+
+        // switch translation off and the keyboard's own set 2 comes through
+        keyboard.write_port(0x64, 0x60);
+        keyboard.write_port(0x60, COMMAND_IRQ1_ENABLE);
+        keyboard.tick(400);
+        let codes: Vec<u8> = (0..6)
+            .map(|_| {
+                keyboard.tick(1);
+                keyboard.read_port(0x60)
+            })
+            .collect();
+        assert_eq!(codes, [0x14, 0x21, 0xf0, 0x21, 0xf0, 0x14]);
+        assert!(keyboard.is_idle());
+
+        // keyboard commands get acked, the A20 gate goes through the output port
+        keyboard.write_port(0x60, 0xf2);
+        assert_eq!(keyboard.read_port(0x60), ACK);
+        keyboard.write_port(0x64, 0xd1);
+        keyboard.write_port(0x60, 0b11);
+        assert!(keyboard.a20_enabled());
+        Ok(())
+    }
+}
@@ -1,9 +1,24 @@
+pub mod address;
+pub mod biu;
+pub mod boot;
 pub mod disassembler;
+pub mod disk;
+pub mod dos;
+pub mod io;
+pub mod keyboard;
 pub mod macros;
+pub mod memory;
 pub mod modrm;
+pub mod mz;
 pub mod opcodes;
 pub mod operation;
+pub mod pic;
+pub mod pit;
 pub mod reg;
+pub mod speaker;
+pub mod text;
+pub mod uart;
+pub mod video;
 
 use std::fmt;
 
@@ -13,3 +13,19 @@ macro_rules! jump_ipinc8_op {
         }
     };
 }
+
+/// Macro for constructing direct far call/jmp instructions followed by an offset and segment
+#[macro_export]
+macro_rules! far_pointer_op {
+    ($mnemonic:path, $value:expr ) => {
+        OpcodeContext {
+            first_byte_raw: $value,
+            mnemonic: $mnemonic,
+            next_field: NextFieldType::FarPointer,
+            d: None,
+            w: None,
+            s: None,
+            reg: None,
+        }
+    };
+}
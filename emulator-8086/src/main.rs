@@ -1,18 +1,36 @@
 use std::path::PathBuf;
 
-use clap::Parser;
-use emulator_8086::disassembler::Disassembler;
+use clap::{Parser, Subcommand};
+use emulator_8086::{disassembler::Disassembler, dos::PSP_SIZE, mz::MzExecutable};
 use log::error;
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    file: PathBuf,
-    #[arg(short, long)]
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(short, long, required = true)]
+    file: Option<PathBuf>,
+    #[arg(short, long, global = true)]
     debug: bool,
+    /// Treat the file as a DOS .COM program loaded at offset 0x100
+    #[arg(long)]
+    com: bool,
+    /// Treat the file as an MZ executable and disassemble its load image
+    #[arg(long, conflicts_with = "com")]
+    exe: bool,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Dump the header and relocation table of an MZ executable
+    MzHeader {
+        #[arg(short, long)]
+        file: PathBuf,
+    },
+}
+
+fn main() {
     let args = Args::parse();
 
     let log_level = if args.debug {
@@ -23,9 +41,35 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     simple_logger::init_with_level(log_level).expect("Failed to init logger!");
 
-    let asm_bin = std::fs::read(args.file)?;
+    let result = match args.command {
+        Some(Command::MzHeader { file }) => mz_header(file),
+        None => disassemble(
+            args.file
+                .expect("clap requires --file without a subcommand"),
+            args.com,
+            args.exe,
+        ),
+    };
 
-    let mut disassembler = Disassembler::new(&asm_bin);
+    // report errors with their Display message, returning them from main would show the Debug form
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn disassemble(file: PathBuf, com: bool, exe: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let asm_bin = std::fs::read(file)?;
+
+    let mut disassembler = if exe {
+        let exe = MzExecutable::parse(&asm_bin)?;
+        Disassembler::new(exe.image()).with_relocations(exe.relocations())
+    } else {
+        Disassembler::new(&asm_bin)
+    };
+    if com {
+        disassembler = disassembler.with_origin(PSP_SIZE as u16);
+    }
     match disassembler.decode() {
         Ok(disassembled) => println!("{}", disassembled),
         Err(e) => error!("{}", e),
@@ -33,3 +77,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+fn mz_header(file: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let exe = MzExecutable::parse(&std::fs::read(file)?)?;
+
+    println!("{}", exe.header());
+    println!("load image size:     {}", exe.image().len());
+    for reloc in exe.relocations() {
+        println!("relocation:          {}", reloc);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,474 @@
+use std::{
+    cell::RefCell,
+    fmt,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+};
+
+use log::{debug, warn};
+
+use crate::{
+    address::{SegmentedAddress, HMA_END, MEMORY_SIZE},
+    text,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Value read back from an address nothing is mapped at
+pub const UNMAPPED_VALUE: u8 = 0xff;
+
+/// Memory-mapped hardware, e.g. video RAM. Devices get the offset into their region rather than the
+/// physical address
+pub trait MemoryDevice {
+    fn read_byte(&mut self, offset: u32) -> u8;
+
+    fn write_byte(&mut self, offset: u32, value: u8);
+}
+
+/// Lets a device be shared, e.g. with the thing rendering it
+impl<T: MemoryDevice> MemoryDevice for Rc<RefCell<T>> {
+    fn read_byte(&mut self, offset: u32) -> u8 {
+        self.borrow_mut().read_byte(offset)
+    }
+
+    fn write_byte(&mut self, offset: u32, value: u8) {
+        self.borrow_mut().write_byte(offset, value)
+    }
+}
+
+#[derive(Debug)]
+pub enum MemoryMapError {
+    Overlap(u32),
+    OutOfRange(u32),
+    InvalidLine(usize, String),
+    UnknownDevice(String),
+}
+
+impl fmt::Display for MemoryMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overlap(address) => write!(f, "Address 0x{:05x} is already mapped", address),
+            Self::OutOfRange(address) => {
+                write!(f, "Address 0x{:05x} is past the end of memory", address)
+            }
+            Self::InvalidLine(line, contents) => {
+                write!(f, "Invalid memory map line {}: '{}'", line, contents)
+            }
+            Self::UnknownDevice(name) => write!(f, "Unknown memory device '{}'", name),
+        }
+    }
+}
+
+impl std::error::Error for MemoryMapError {}
+
+enum Region {
+    Ram(Vec<u8>),
+    Rom(Vec<u8>),
+    Device(Box<dyn MemoryDevice>),
+}
+
+impl fmt::Debug for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ram(_) => write!(f, "Ram"),
+            Self::Rom(_) => write!(f, "Rom"),
+            Self::Device(_) => write!(f, "Device"),
+        }
+    }
+}
+
+/// The physical address space, made up of RAM, ROM and device regions
+#[derive(Debug, Default)]
+pub struct MemoryMap {
+    regions: Vec<(RangeInclusive<u32>, Region)>,
+    a20_enabled: bool,
+    log_rom_writes: bool,
+    ignored_rom_writes: usize,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let addresses past 1 MiB through to the HMA instead of wrapping
+    pub fn with_a20(mut self, a20_enabled: bool) -> Self {
+        self.a20_enabled = a20_enabled;
+        self
+    }
+
+    /// Warn about writes to ROM - they're ignored either way, but usually mean a bug in the program
+    pub fn with_rom_write_logging(mut self, log_rom_writes: bool) -> Self {
+        self.log_rom_writes = log_rom_writes;
+        self
+    }
+
+    /// For when a program flips the A20 gate while running
+    pub fn set_a20(&mut self, a20_enabled: bool) {
+        self.a20_enabled = a20_enabled;
+    }
+
+    pub fn a20_enabled(&self) -> bool {
+        self.a20_enabled
+    }
+
+    /// How many writes to ROM have been dropped
+    pub fn ignored_rom_writes(&self) -> usize {
+        self.ignored_rom_writes
+    }
+
+    pub fn map_ram(&mut self, range: RangeInclusive<u32>) -> Result<()> {
+        // check first, a bad range shouldn't get as far as allocating
+        self.check_free(&range)?;
+        let size = (range.end() - range.start()) as usize + 1;
+        self.map(range, Region::Ram(vec![0; size]))
+    }
+
+    pub fn map_rom(&mut self, start: u32, image: Vec<u8>) -> Result<()> {
+        let end = u32::try_from(image.len())
+            .ok()
+            .and_then(|len| len.checked_sub(1))
+            .and_then(|last| start.checked_add(last))
+            .ok_or(MemoryMapError::OutOfRange(start))?;
+        self.map(start..=end, Region::Rom(image))
+    }
+
+    pub fn load_rom<P: AsRef<Path>>(&mut self, start: u32, path: P) -> Result<()> {
+        self.map_rom(start, std::fs::read(path)?)
+    }
+
+    pub fn map_device(
+        &mut self,
+        range: RangeInclusive<u32>,
+        device: Box<dyn MemoryDevice>,
+    ) -> Result<()> {
+        self.map(range, Region::Device(device))
+    }
+
+    fn map(&mut self, range: RangeInclusive<u32>, region: Region) -> Result<()> {
+        self.check_free(&range)?;
+        self.regions.push((range, region));
+        Ok(())
+    }
+
+    /// Make sure `range` is inside the address space and nothing is mapped there yet
+    fn check_free(&self, range: &RangeInclusive<u32>) -> Result<()> {
+        if *range.end() > HMA_END || range.is_empty() {
+            return Err(Box::new(MemoryMapError::OutOfRange(*range.end())));
+        }
+
+        let conflict = self
+            .regions
+            .iter()
+            .map(|(mapped, _)| mapped)
+            .find(|mapped| mapped.start() <= range.end() && range.start() <= mapped.end());
+        if let Some(mapped) = conflict {
+            let address = *mapped.start().max(range.start());
+            return Err(Box::new(MemoryMapError::Overlap(address)));
+        }
+
+        Ok(())
+    }
+
+    fn region_for(&mut self, address: u32) -> Option<(u32, &mut Region)> {
+        let address = if self.a20_enabled {
+            address
+        } else {
+            address & (MEMORY_SIZE as u32 - 1)
+        };
+
+        self.regions
+            .iter_mut()
+            .find(|(range, _)| range.contains(&address))
+            .map(|(range, region)| (address - range.start(), region))
+    }
+
+    pub fn read_byte(&mut self, address: u32) -> u8 {
+        match self.region_for(address) {
+            Some((offset, Region::Ram(data) | Region::Rom(data))) => data[offset as usize],
+            Some((offset, Region::Device(device))) => device.read_byte(offset),
+            None => {
+                debug!("read from unmapped address 0x{:05x}", address);
+                UNMAPPED_VALUE
+            }
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u32, value: u8) {
+        let log_rom_writes = self.log_rom_writes;
+
+        match self.region_for(address) {
+            Some((offset, Region::Ram(data))) => data[offset as usize] = value,
+            Some((offset, Region::Device(device))) => device.write_byte(offset, value),
+            Some((_, Region::Rom(_))) => {
+                if log_rom_writes {
+                    warn!("write of 0x{:02x} to rom at 0x{:05x}", value, address);
+                }
+                self.ignored_rom_writes += 1;
+            }
+            None => debug!(
+                "write of 0x{:02x} to unmapped address 0x{:05x}",
+                value, address
+            ),
+        }
+    }
+
+    pub fn read_bytes(&mut self, address: u32, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.read_byte(address.wrapping_add(i as u32)))
+            .collect()
+    }
+
+    pub fn write_bytes(&mut self, address: u32, data: &[u8]) {
+        for (i, value) in data.iter().enumerate() {
+            self.write_byte(address.wrapping_add(i as u32), *value);
+        }
+    }
+
+    /// Bytes of RAM from `address` up to the first address that isn't RAM, for loaders to size what they put there
+    pub fn ram_available(&self, address: u32) -> usize {
+        let mut end = address;
+        while let Some((range, _)) = self
+            .regions
+            .iter()
+            .find(|(range, region)| matches!(region, Region::Ram(_)) && range.contains(&end))
+        {
+            end = range.end() + 1;
+        }
+        (end - address) as usize
+    }
+
+    pub fn physical(&self, address: SegmentedAddress) -> u32 {
+        address.physical(self.a20_enabled)
+    }
+
+    pub fn read_byte_at(&mut self, address: SegmentedAddress) -> u8 {
+        self.read_byte(self.physical(address))
+    }
+
+    pub fn write_byte_at(&mut self, address: SegmentedAddress, value: u8) {
+        self.write_byte(self.physical(address), value)
+    }
+
+    /// A word at offset ffff gets its high byte from offset 0 of the same segment
+    pub fn read_word_at(&mut self, address: SegmentedAddress) -> u16 {
+        let low = self.read_byte_at(address) as u16;
+        let high = (self.read_byte_at(address.wrapping_add(1)) as u16) << 8;
+        low + high
+    }
+
+    pub fn write_word_at(&mut self, address: SegmentedAddress, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write_byte_at(address, low);
+        self.write_byte_at(address.wrapping_add(1), high);
+    }
+}
+
+/// One region from a memory map file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegionConfig {
+    Ram(RangeInclusive<u32>),
+    Rom {
+        start: u32,
+        path: PathBuf,
+    },
+    Device {
+        name: String,
+        range: RangeInclusive<u32>,
+    },
+}
+
+/// A memory map described in a text file, one region per line, `#` starts a comment. Addresses are
+/// physical (hex with 0x, or decimal) or segment:offset in hex:
+///
+/// ```text
+/// ram 0x00000 0x9ffff
+/// device cga b800:0000 b800:3fff
+/// rom f000:0000 bios.bin
+/// a20 off
+/// log-rom-writes on
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryMapConfig {
+    pub regions: Vec<RegionConfig>,
+    pub a20_enabled: bool,
+    pub log_rom_writes: bool,
+}
+
+impl MemoryMapConfig {
+    /// Read a config file, ROM paths are relative to the file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut config: Self = std::fs::read_to_string(path)?.parse()?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        for region in config.regions.iter_mut() {
+            if let RegionConfig::Rom { path, .. } = region {
+                *path = base.join(&*path);
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Build the map, looking up each named device region with `device`
+    pub fn build(
+        &self,
+        mut device: impl FnMut(&str) -> Option<Box<dyn MemoryDevice>>,
+    ) -> Result<MemoryMap> {
+        let mut map = MemoryMap::new()
+            .with_a20(self.a20_enabled)
+            .with_rom_write_logging(self.log_rom_writes);
+
+        for region in &self.regions {
+            match region {
+                RegionConfig::Ram(range) => map.map_ram(range.clone())?,
+                RegionConfig::Rom { start, path } => map.load_rom(*start, path)?,
+                RegionConfig::Device { name, range } => {
+                    let handler =
+                        device(name).ok_or_else(|| MemoryMapError::UnknownDevice(name.clone()))?;
+                    map.map_device(range.clone(), handler)?;
+                }
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+impl FromStr for MemoryMapConfig {
+    type Err = MemoryMapError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut config = Self::default();
+
+        for (line_number, raw_line, line) in text::lines(s) {
+            let invalid = || MemoryMapError::InvalidLine(line_number, raw_line.to_owned());
+            let address = |s: &str| parse_address(s).ok_or_else(invalid);
+            let switch = |s: &str| match s {
+                "on" => Ok(true),
+                "off" => Ok(false),
+                _ => Err(invalid()),
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            match fields.as_slice() {
+                ["ram", start, end] => config
+                    .regions
+                    .push(RegionConfig::Ram(address(start)?..=address(end)?)),
+                ["rom", start, path] => config.regions.push(RegionConfig::Rom {
+                    start: address(start)?,
+                    path: path.into(),
+                }),
+                ["device", name, start, end] => config.regions.push(RegionConfig::Device {
+                    name: name.to_string(),
+                    range: address(start)?..=address(end)?,
+                }),
+                ["a20", value] => config.a20_enabled = switch(value)?,
+                ["log-rom-writes", value] => config.log_rom_writes = switch(value)?,
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_address(s: &str) -> Option<u32> {
+    if let Some((segment, offset)) = s.split_once(':') {
+        let hex = |s: &str| u16::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok();
+        return Some(SegmentedAddress::new(hex(segment)?, hex(offset)?).physical(true));
+    }
+
+    text::parse_number(s)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Remembers the last offset written
+    #[derive(Default)]
+    struct Probe {
+        last_write: Option<(u32, u8)>,
+    }
+
+    impl MemoryDevice for Probe {
+        fn read_byte(&mut self, offset: u32) -> u8 {
+            offset as u8
+        }
+
+        fn write_byte(&mut self, offset: u32, value: u8) {
+            self.last_write = Some((offset, value));
+        }
+    }
+
+    #[test]
+    fn test_memory_map() -> Result<()> {
+        let config: MemoryMapConfig = "
+            # 640K, some video memory and a tiny rom
+            ram 0x00000 0x9ffff
+            device probe b800:0000 b800:3fff
+            rom f000:fff0 reset.bin
+            log-rom-writes on
+        "
+        .parse()?;
+        assert_eq!(
+            config.regions[1],
+            RegionConfig::Device {
+                name: "probe".into(),
+                range: 0xb8000..=0xbbfff,
+            }
+        );
+        assert!("ram 0 0 0".parse::<MemoryMapConfig>().is_err());
+
+        let probe = Rc::new(RefCell::new(Probe::default()));
+        let without_rom = MemoryMapConfig {
+            regions: config.regions[..2].to_vec(),
+            ..config
+        };
+        let mut memory = without_rom.build(|name| match name {
+            "probe" => Some(Box::new(probe.clone())),
+            _ => None,
+        })?;
+        assert!(memory.map_ram(0x9f000..=0xa0fff).is_err());
+        // backwards or wrapping ranges are errors, not panics
+        let backwards: MemoryMapConfig = "ram 0x9ffff 0x00000".parse()?;
+        assert!(backwards.build(|_| None).is_err());
+        assert!(memory.map_rom(0xffff_fff0, vec![0; 0x20]).is_err());
+        assert!(memory.map_rom(0xc0000, Vec::new()).is_err());
+        // jmp f000:e05b
+        memory.map_rom(0xffff0, vec![0xea, 0x5b, 0xe0, 0x00, 0xf0])?;
+
+        memory.write_word_at(SegmentedAddress::new(0x1000, 0xffff), 0x1234);
+        assert_eq!(memory.read_byte(0x1ffff), 0x34);
+        assert_eq!(memory.read_byte(0x10000), 0x12);
+
+        memory.write_byte_at(SegmentedAddress::new(0xb800, 0x0010), 0x07);
+        assert_eq!(probe.borrow().last_write, Some((0x10, 0x07)));
+        assert_eq!(memory.read_byte(0xb8020), 0x20);
+
+        // writes to rom go nowhere
+        memory.write_byte(0xffff0, 0x90);
+        assert_eq!(memory.read_byte(0xffff0), 0xea);
+        assert_eq!(memory.ignored_rom_writes(), 1);
+        assert_eq!(memory.read_byte(0xc0000), UNMAPPED_VALUE);
+        assert_eq!(memory.ram_available(0x9fff0), 0x10);
+        assert_eq!(memory.ram_available(0xb8000), 0);
+
+        // FFFF:0010 wraps to 0 until A20 is on, then there's nothing mapped there
+        memory.write_byte(0, 0x42);
+        assert_eq!(
+            memory.read_byte_at(SegmentedAddress::new(0xffff, 0x10)),
+            0x42
+        );
+        memory.set_a20(true);
+        assert_eq!(
+            memory.read_byte_at(SegmentedAddress::new(0xffff, 0x10)),
+            UNMAPPED_VALUE
+        );
+        Ok(())
+    }
+}
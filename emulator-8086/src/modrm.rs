@@ -1,6 +1,9 @@
 use std::fmt;
 
-use crate::{reg::Register, DissassemblerError, IsWord};
+use crate::{
+    reg::{Register, SegmentRegister},
+    DissassemblerError, IsWord,
+};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -59,7 +62,7 @@ impl TryFrom<u8> for Mode {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum EffectiveAddress {
     DirectAddress,
     SingleReg(Register),
@@ -109,11 +112,37 @@ impl fmt::Display for EffectiveAddress {
 }
 
 impl EffectiveAddress {
+    /// Anything based off BP is on the stack, everything else is data
+    pub fn default_segment(&self) -> SegmentRegister {
+        match self {
+            Self::SingleReg(Register::BP) | Self::DoubleReg(Register::BP, _) => SegmentRegister::SS,
+            _ => SegmentRegister::DS,
+        }
+    }
+
+    /// Offset into the segment, given the register values. Byte displacements are sign extended and the sum
+    /// wraps at 64K
+    pub fn offset(&self, disp: &DisplacementValue, read_register: impl Fn(Register) -> u16) -> u16 {
+        let disp = match disp {
+            DisplacementValue::None => 0,
+            DisplacementValue::Byte(b) => *b as i8 as u16,
+            DisplacementValue::Word(w) => *w,
+        };
+
+        match self {
+            Self::DirectAddress => disp,
+            Self::SingleReg(reg) => read_register(*reg).wrapping_add(disp),
+            Self::DoubleReg(first, second) => read_register(*first)
+                .wrapping_add(read_register(*second))
+                .wrapping_add(disp),
+        }
+    }
+
     pub fn to_string_with_displacement(&self, disp: &DisplacementValue) -> String {
         let mut s = String::new();
         match self {
             Self::DirectAddress => {
-                s.push_str(&format!("[{}]", disp.to_string()));
+                s.push_str(&format!("[{}]", disp));
             }
             Self::SingleReg(reg) => {
                 s.push_str(&format!("[{}", reg));
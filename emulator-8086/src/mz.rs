@@ -0,0 +1,398 @@
+use std::fmt;
+
+use crate::{
+    dos::{
+        write_psp, LoadedProgram, LoaderError, DEFAULT_LOAD_SEGMENT, DEFAULT_MEMORY_TOP, PSP_SIZE,
+    },
+    memory::MemoryMap,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Size of the fixed part of the header, the relocation table usually follows right after
+const HEADER_LEN: usize = 0x1c;
+const PAGE_SIZE: usize = 512;
+const PARAGRAPH_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub enum MzError {
+    InvalidSignature(u16),
+    Truncated,
+    InvalidPageCount,
+    RelocationOutOfBounds(Relocation),
+}
+
+impl fmt::Display for MzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSignature(sig) => write!(f, "Invalid MZ signature 0x{:04x}", sig),
+            Self::Truncated => write!(f, "File is shorter than its header says"),
+            Self::InvalidPageCount => write!(f, "Header has no pages"),
+            Self::RelocationOutOfBounds(reloc) => {
+                write!(f, "Relocation {} points outside the load image", reloc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MzError {}
+
+/// The fixed part of the MZ header, field names follow the usual `e_*` ones without the prefix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MzHeader {
+    /// Bytes used in the last page, 0 means the whole page
+    pub last_page_bytes: u16,
+    /// 512 byte pages in the file, including the header
+    pub pages: u16,
+    pub relocation_count: u16,
+    pub header_paragraphs: u16,
+    /// Paragraphs needed past the end of the image
+    pub min_alloc: u16,
+    /// Paragraphs wanted past the end of the image
+    pub max_alloc: u16,
+    pub initial_ss: u16,
+    pub initial_sp: u16,
+    pub checksum: u16,
+    pub initial_ip: u16,
+    pub initial_cs: u16,
+    /// File offset of the relocation table
+    pub relocation_offset: u16,
+    pub overlay: u16,
+}
+
+impl MzHeader {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN {
+            return Err(Box::new(MzError::Truncated));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+
+        // some very old linkers wrote ZM instead
+        let signature = word(0x00);
+        if signature != u16::from_le_bytes(*b"MZ") && signature != u16::from_le_bytes(*b"ZM") {
+            return Err(Box::new(MzError::InvalidSignature(signature)));
+        }
+
+        let header = Self {
+            last_page_bytes: word(0x02),
+            pages: word(0x04),
+            relocation_count: word(0x06),
+            header_paragraphs: word(0x08),
+            min_alloc: word(0x0a),
+            max_alloc: word(0x0c),
+            initial_ss: word(0x0e),
+            initial_sp: word(0x10),
+            checksum: word(0x12),
+            initial_ip: word(0x14),
+            initial_cs: word(0x16),
+            relocation_offset: word(0x18),
+            overlay: word(0x1a),
+        };
+
+        if header.pages == 0 {
+            return Err(Box::new(MzError::InvalidPageCount));
+        }
+
+        Ok(header)
+    }
+
+    /// Size of the file as described by the header
+    pub fn file_size(&self) -> usize {
+        let last_page = match self.last_page_bytes as usize {
+            0 => PAGE_SIZE,
+            bytes => bytes,
+        };
+        (self.pages as usize - 1) * PAGE_SIZE + last_page
+    }
+
+    pub fn header_size(&self) -> usize {
+        self.header_paragraphs as usize * PARAGRAPH_SIZE
+    }
+}
+
+impl fmt::Display for MzHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "bytes in last page:  {}", self.last_page_bytes)?;
+        writeln!(f, "pages:               {}", self.pages)?;
+        writeln!(f, "file size:           {}", self.file_size())?;
+        writeln!(f, "relocations:         {}", self.relocation_count)?;
+        writeln!(f, "header paragraphs:   {}", self.header_paragraphs)?;
+        writeln!(f, "min alloc:           0x{:04x}", self.min_alloc)?;
+        writeln!(f, "max alloc:           0x{:04x}", self.max_alloc)?;
+        writeln!(
+            f,
+            "initial ss:sp:       {:04x}:{:04x}",
+            self.initial_ss, self.initial_sp
+        )?;
+        writeln!(f, "checksum:            0x{:04x}", self.checksum)?;
+        writeln!(
+            f,
+            "initial cs:ip:       {:04x}:{:04x}",
+            self.initial_cs, self.initial_ip
+        )?;
+        writeln!(f, "relocation table:    0x{:04x}", self.relocation_offset)?;
+        write!(f, "overlay:             {}", self.overlay)
+    }
+}
+
+/// A segment word in the load image that needs the load segment added to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u16,
+    pub segment: u16,
+}
+
+impl Relocation {
+    /// Offset of the fixup from the start of the load image
+    pub fn image_offset(&self) -> usize {
+        ((self.segment as usize) << 4) + self.offset as usize
+    }
+}
+
+impl fmt::Display for Relocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.segment, self.offset)
+    }
+}
+
+#[derive(Debug)]
+pub struct MzExecutable {
+    header: MzHeader,
+    relocations: Vec<Relocation>,
+    image: Vec<u8>,
+}
+
+impl MzExecutable {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let header = MzHeader::parse(bytes)?;
+
+        let table_start = header.relocation_offset as usize;
+        let table_end = table_start + header.relocation_count as usize * 4;
+        let table = bytes
+            .get(table_start..table_end)
+            .ok_or(MzError::Truncated)?;
+
+        let relocations = table
+            .chunks_exact(4)
+            .map(|entry| Relocation {
+                offset: u16::from_le_bytes([entry[0], entry[1]]),
+                segment: u16::from_le_bytes([entry[2], entry[3]]),
+            })
+            .collect::<Vec<Relocation>>();
+
+        // anything past the size in the header is overlay data, not part of the load image
+        let image = bytes
+            .get(header.header_size()..header.file_size().min(bytes.len()))
+            .ok_or(MzError::Truncated)?
+            .to_vec();
+
+        // catch bad fixups here rather than halfway through loading
+        if let Some(reloc) = relocations
+            .iter()
+            .find(|reloc| reloc.image_offset() + 2 > image.len())
+        {
+            return Err(Box::new(MzError::RelocationOutOfBounds(*reloc)));
+        }
+
+        Ok(Self {
+            header,
+            relocations,
+            image,
+        })
+    }
+
+    pub fn header(&self) -> &MzHeader {
+        &self.header
+    }
+
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
+
+    /// The load image, before relocation
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    /// Paragraphs taken up by the load image
+    pub fn image_paragraphs(&self) -> usize {
+        self.image.len().div_ceil(PARAGRAPH_SIZE)
+    }
+}
+
+/// Loads an MZ executable into RAM the same way DOS does
+#[derive(Debug)]
+pub struct ExeLoader {
+    segment: u16,
+    memory_top: u16,
+    command_tail: String,
+}
+
+impl Default for ExeLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExeLoader {
+    pub fn new() -> Self {
+        Self {
+            segment: DEFAULT_LOAD_SEGMENT,
+            memory_top: DEFAULT_MEMORY_TOP,
+            command_tail: String::new(),
+        }
+    }
+
+    /// Segment the PSP goes at, the load image starts in the paragraph after it
+    pub fn with_segment(mut self, segment: u16) -> Self {
+        self.segment = segment;
+        self
+    }
+
+    pub fn with_memory_top(mut self, memory_top: u16) -> Self {
+        self.memory_top = memory_top;
+        self
+    }
+
+    pub fn with_command_tail(mut self, tail: &str) -> Self {
+        self.command_tail = tail.to_owned();
+        self
+    }
+
+    /// Segment the load image starts at - relocations and the initial CS/SS are relative to this
+    pub fn load_segment(&self) -> Result<u16> {
+        Ok(self
+            .segment
+            .checked_add((PSP_SIZE / PARAGRAPH_SIZE) as u16)
+            .ok_or(LoaderError::OutOfMemory)?)
+    }
+
+    /// Write the PSP and relocated image into memory. DS and ES point at the PSP, CS:IP and SS:SP come from the
+    /// header
+    pub fn load(&self, memory: &mut MemoryMap, exe: &MzExecutable) -> Result<LoadedProgram> {
+        let header = exe.header();
+        let load_segment = self.load_segment()?;
+        let base = (load_segment as u32) << 4;
+
+        // the program gets at least min_alloc past the image, and up to max_alloc if there's room
+        // TODO: min_alloc == max_alloc == 0 means load as high as possible, we always load low
+        let ram_top = (base as usize + memory.ram_available(base)) / PARAGRAPH_SIZE;
+        let top = (self.memory_top as usize).min(ram_top);
+        let available = top.saturating_sub(load_segment as usize);
+        let needed = exe.image_paragraphs() + header.min_alloc as usize;
+        if needed > available {
+            return Err(Box::new(LoaderError::OutOfMemory));
+        }
+        let allocated = (exe.image_paragraphs() + header.max_alloc as usize).min(available);
+
+        let program_top = (load_segment as usize + allocated) as u16;
+        write_psp(memory, self.segment, program_top, &self.command_tail)?;
+
+        memory.write_bytes(base, exe.image());
+
+        // parse has already checked these all land inside the image
+        for reloc in exe.relocations() {
+            let addr = base + reloc.image_offset() as u32;
+            let word = memory.read_bytes(addr, 2);
+            let fixed = u16::from_le_bytes([word[0], word[1]]).wrapping_add(load_segment);
+            memory.write_bytes(addr, &fixed.to_le_bytes());
+        }
+
+        Ok(LoadedProgram {
+            psp_segment: self.segment,
+            cs: load_segment.wrapping_add(header.initial_cs),
+            ip: header.initial_ip,
+            ss: load_segment.wrapping_add(header.initial_ss),
+            sp: header.initial_sp,
+            ds: self.segment,
+            es: self.segment,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::disassembler::Disassembler;
+
+    /// 2 paragraph header with one relocation, then a `call 0001:0000` and a 16 byte stack
+    fn test_exe() -> Vec<u8> {
+        let mut exe = vec![0u8; 0x20];
+        exe[0x00..0x02].copy_from_slice(b"MZ");
+        // 32 byte header + 16 byte image
+        exe[0x02..0x04].copy_from_slice(&48u16.to_le_bytes());
+        exe[0x04..0x06].copy_from_slice(&1u16.to_le_bytes());
+        exe[0x06..0x08].copy_from_slice(&1u16.to_le_bytes());
+        exe[0x08..0x0a].copy_from_slice(&2u16.to_le_bytes());
+        exe[0x0a..0x0c].copy_from_slice(&1u16.to_le_bytes());
+        exe[0x0c..0x0e].copy_from_slice(&0xffffu16.to_le_bytes());
+        exe[0x0e..0x10].copy_from_slice(&1u16.to_le_bytes());
+        exe[0x10..0x12].copy_from_slice(&0x10u16.to_le_bytes());
+        exe[0x18..0x1a].copy_from_slice(&0x1cu16.to_le_bytes());
+        // relocation for the segment half of the far call
+        exe[0x1c..0x20].copy_from_slice(&[0x03, 0x00, 0x00, 0x00]);
+
+        exe.extend_from_slice(&[0x9a, 0x00, 0x00, 0x01, 0x00]);
+        exe.resize(48, 0x90);
+        exe
+    }
+
+    #[test]
+    fn test_parse_and_load() -> Result<()> {
+        let exe = MzExecutable::parse(&test_exe())?;
+        assert_eq!(exe.header().file_size(), 48);
+        assert_eq!(
+            exe.relocations(),
+            &[Relocation {
+                offset: 3,
+                segment: 0
+            }]
+        );
+        assert_eq!(exe.image().len(), 16);
+
+        let call = Disassembler::new(exe.image())
+            .with_relocations(exe.relocations())
+            .decode_next_op()?
+            .unwrap();
+        assert_eq!(call.to_string(), "call seg_0001:0x0000");
+        let call = Disassembler::new(exe.image()).decode_next_op()?.unwrap();
+        assert_eq!(call.to_string(), "call 0x0001:0x0000");
+
+        let mut memory = MemoryMap::new();
+        memory.map_ram(0x00000..=0x9ffff)?;
+        let loaded = ExeLoader::new()
+            .with_segment(0x1000)
+            .load(&mut memory, &exe)?;
+
+        assert_eq!(loaded.ds, 0x1000);
+        assert_eq!((loaded.cs, loaded.ip), (0x1010, 0));
+        assert_eq!((loaded.ss, loaded.sp), (0x1011, 0x10));
+        // call 0001:0000 is now call 1011:0000
+        assert_eq!(
+            memory.read_bytes(0x10100, 5),
+            [0x9a, 0x00, 0x00, 0x11, 0x10]
+        );
+        // max alloc of 0xffff takes everything up to the top of conventional memory
+        assert_eq!(
+            memory.read_bytes(0x10002, 2),
+            DEFAULT_MEMORY_TOP.to_le_bytes()
+        );
+
+        // no room for the image past a PSP right at the top of the address space, or at the top of RAM
+        assert!(ExeLoader::new()
+            .with_segment(0xfff8)
+            .load(&mut memory, &exe)
+            .is_err());
+        assert!(ExeLoader::new()
+            .with_segment(0x9ff0)
+            .load(&mut memory, &exe)
+            .is_err());
+
+        // a fixup past the end of the image is rejected up front
+        let mut bad = test_exe();
+        bad[0x1c] = 0x0f;
+        assert!(MzExecutable::parse(&bad).is_err());
+        Ok(())
+    }
+}
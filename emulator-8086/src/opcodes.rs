@@ -1,6 +1,8 @@
 use core::{fmt, panic};
 
-use crate::{jump_ipinc8_op, reg::Register, DestinationIsReg, DissassemblerError, IsWord};
+use crate::{
+    far_pointer_op, jump_ipinc8_op, reg::Register, DestinationIsReg, DissassemblerError, IsWord,
+};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum OpcodeMnemonic {
@@ -28,6 +30,8 @@ pub enum OpcodeMnemonic {
     Loopz,
     Loopnz,
     Jcxz,
+    Call,
+    Jmp,
     NeedsNextByte,
 }
 
@@ -62,6 +66,8 @@ impl fmt::Display for OpcodeMnemonic {
                 Self::Loopz => "loopz",
                 Self::Loopnz => "loopnz",
                 Self::Jcxz => "jcxz",
+                Self::Call => "call",
+                Self::Jmp => "jmp",
                 Self::NeedsNextByte => todo!(),
             }
         )
@@ -100,6 +106,8 @@ pub enum NextFieldType {
     Data,
     Addr,
     IpInc8,
+    /// Offset then segment, for direct far calls and jumps
+    FarPointer,
     None,
 }
 
@@ -277,6 +285,10 @@ impl TryFrom<u8> for OpcodeContext {
             0b11100000 => jump_ipinc8_op!(OpcodeMnemonic::Loopnz, value),
             // jcxz
             0b11100011 => jump_ipinc8_op!(OpcodeMnemonic::Jcxz, value),
+            // call, direct intersegment
+            0b10011010 => far_pointer_op!(OpcodeMnemonic::Call, value),
+            // jmp, direct intersegment
+            0b11101010 => far_pointer_op!(OpcodeMnemonic::Jmp, value),
             _ => return Err(DissassemblerError::InvalidOpcode(value)),
         })
     }
@@ -13,6 +13,12 @@ pub enum Operand {
     DataByte(u8),
     DataWord(u16),
     SignedJump(i8),
+    /// `segment:offset`, `relocated` when the segment is fixed up at load time so has no meaning on its own
+    FarPointer {
+        segment: u16,
+        offset: u16,
+        relocated: bool,
+    },
 }
 
 // TODO: move all the string formatting stuff here
@@ -29,6 +35,17 @@ impl fmt::Display for Operand {
                 Operand::DataByte(b) => b.to_string(),
                 Operand::DataWord(w) => w.to_string(),
                 Operand::SignedJump(j) => j.to_string(),
+                Operand::FarPointer {
+                    segment,
+                    offset,
+                    relocated,
+                } => {
+                    if *relocated {
+                        format!("seg_{:04x}:0x{:04x}", segment, offset)
+                    } else {
+                        format!("0x{:04x}:0x{:04x}", segment, offset)
+                    }
+                }
             }
         )
     }
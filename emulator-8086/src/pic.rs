@@ -0,0 +1,357 @@
+use std::{cell::RefCell, rc::Rc};
+
+use log::debug;
+
+use crate::io::IoDevice;
+
+/// Where the PC puts the (master) PIC
+pub const PIC_PORTS: std::ops::RangeInclusive<u16> = 0x20..=0x21;
+/// Vector base the PC BIOS programs, IRQ0 comes in as int 08h
+pub const PC_VECTOR_BASE: u8 = 0x08;
+
+/// A device's connection to one of the PIC's interrupt request inputs
+#[derive(Clone, Debug)]
+pub struct IrqLine {
+    pic: Rc<RefCell<Pic>>,
+    irq: u8,
+}
+
+impl IrqLine {
+    pub fn new(pic: Rc<RefCell<Pic>>, irq: u8) -> Self {
+        Self { pic, irq }
+    }
+
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    /// Drive the line high or low, the PIC picks up the request on the rising edge
+    pub fn set(&self, level: bool) {
+        self.pic.borrow_mut().set_irq(self.irq, level);
+    }
+
+    /// Pulse the line, for devices that just want to raise a request
+    pub fn raise(&self) {
+        self.set(true);
+        self.set(false);
+    }
+}
+
+/// Which initialization command word we're expecting next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InitState {
+    Ready,
+    Icw2,
+    Icw3,
+    Icw4,
+}
+
+/// 8259A programmable interrupt controller. Only a single (master) PIC is modeled, cascading isn't supported
+#[derive(Debug)]
+pub struct Pic {
+    init: InitState,
+    needs_icw4: bool,
+    single: bool,
+    level_triggered: bool,
+    auto_eoi: bool,
+    rotate_on_auto_eoi: bool,
+    special_mask: bool,
+    read_isr: bool,
+    poll: bool,
+    vector_base: u8,
+    /// Interrupt request register
+    irr: u8,
+    /// In-service register
+    isr: u8,
+    /// Interrupt mask register
+    imr: u8,
+    /// Current level of each input line, for edge detection
+    lines: u8,
+    /// IRQ with the lowest priority, the one after it has the highest
+    lowest_priority: u8,
+}
+
+impl Default for Pic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pic {
+    /// A PIC as the PC BIOS leaves it - edge triggered, vectors at 08h, nothing masked
+    pub fn new() -> Self {
+        Self {
+            init: InitState::Ready,
+            needs_icw4: false,
+            single: true,
+            level_triggered: false,
+            auto_eoi: false,
+            rotate_on_auto_eoi: false,
+            special_mask: false,
+            read_isr: false,
+            poll: false,
+            vector_base: PC_VECTOR_BASE,
+            irr: 0,
+            isr: 0,
+            imr: 0,
+            lines: 0,
+            lowest_priority: 7,
+        }
+    }
+
+    /// Wrap up in an `Rc` so devices can get `IrqLine`s to it and it can still go on the I/O bus
+    pub fn shared(self) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(self))
+    }
+
+    pub fn irr(&self) -> u8 {
+        self.irr
+    }
+
+    pub fn isr(&self) -> u8 {
+        self.isr
+    }
+
+    pub fn imr(&self) -> u8 {
+        self.imr
+    }
+
+    /// Update the level of an IRQ input
+    pub fn set_irq(&mut self, irq: u8, level: bool) {
+        let bit = 1 << (irq & 0b111);
+        let was_high = self.lines & bit != 0;
+
+        if level {
+            self.lines |= bit;
+            if self.level_triggered || !was_high {
+                self.irr |= bit;
+            }
+        } else {
+            self.lines &= !bit;
+            // a level triggered request goes away as soon as the line drops
+            if self.level_triggered {
+                self.irr &= !bit;
+            }
+        }
+    }
+
+    /// State of the INTR output, the CPU checks this between instructions (when IF is set)
+    pub fn interrupt_pending(&self) -> bool {
+        self.next_irq().is_some()
+    }
+
+    /// INTA cycle - returns the vector for the highest priority request and marks it in service
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let irq = self.next_irq()?;
+        let bit = 1 << irq;
+
+        if !self.level_triggered {
+            self.irr &= !bit;
+        }
+
+        if self.auto_eoi {
+            if self.rotate_on_auto_eoi {
+                self.lowest_priority = irq;
+            }
+        } else {
+            self.isr |= bit;
+        }
+
+        debug!("pic acknowledged irq {}", irq);
+        Some(self.vector_base + irq)
+    }
+
+    /// IRQs in priority order, highest first
+    fn priority_order(&self) -> impl Iterator<Item = u8> {
+        let first = (self.lowest_priority + 1) & 0b111;
+        (0..8).map(move |i| (first + i) & 0b111)
+    }
+
+    fn next_irq(&self) -> Option<u8> {
+        let requested = self.irr & !self.imr;
+
+        for irq in self.priority_order() {
+            let bit = 1 << irq;
+            // in special mask mode only the in-service IRQ itself is blocked, lower priorities can still get in
+            if self.isr & bit != 0 && !self.special_mask {
+                return None;
+            }
+            if requested & bit != 0 && self.isr & bit == 0 {
+                return Some(irq);
+            }
+        }
+
+        None
+    }
+
+    /// Highest priority IRQ currently in service
+    fn highest_in_service(&self) -> Option<u8> {
+        self.priority_order().find(|irq| self.isr & (1 << irq) != 0)
+    }
+
+    fn write_command(&mut self, value: u8) {
+        if value & 0b0001_0000 != 0 {
+            // ICW1 - start of the initialization sequence
+            self.needs_icw4 = value & 0b0001 != 0;
+            self.single = value & 0b0010 != 0;
+            self.level_triggered = value & 0b1000 != 0;
+            self.auto_eoi = false;
+            self.rotate_on_auto_eoi = false;
+            self.special_mask = false;
+            self.read_isr = false;
+            self.imr = 0;
+            self.isr = 0;
+            self.irr = 0;
+            self.lowest_priority = 7;
+            self.init = InitState::Icw2;
+        } else if value & 0b0000_1000 != 0 {
+            self.write_ocw3(value);
+        } else {
+            self.write_ocw2(value);
+        }
+    }
+
+    /// OCW2 - end of interrupt and priority rotation
+    fn write_ocw2(&mut self, value: u8) {
+        let level = value & 0b111;
+
+        match value >> 5 {
+            // non-specific EOI, optionally rotating
+            0b001 | 0b101 => {
+                if let Some(irq) = self.highest_in_service() {
+                    self.isr &= !(1 << irq);
+                    if value >> 5 == 0b101 {
+                        self.lowest_priority = irq;
+                    }
+                }
+            }
+            // specific EOI, optionally rotating
+            0b011 | 0b111 => {
+                self.isr &= !(1 << level);
+                if value >> 5 == 0b111 {
+                    self.lowest_priority = level;
+                }
+            }
+            0b100 => self.rotate_on_auto_eoi = true,
+            0b000 => self.rotate_on_auto_eoi = false,
+            0b110 => self.lowest_priority = level,
+            // 010 is a no-op
+            _ => (),
+        }
+    }
+
+    /// OCW3 - special mask mode, poll and which register reads back
+    fn write_ocw3(&mut self, value: u8) {
+        if value & 0b0100_0000 != 0 {
+            self.special_mask = value & 0b0010_0000 != 0;
+        }
+        if value & 0b0010 != 0 {
+            self.read_isr = value & 0b0001 != 0;
+        }
+        self.poll = value & 0b0100 != 0;
+    }
+
+    fn write_data(&mut self, value: u8) {
+        match self.init {
+            InitState::Icw2 => {
+                // low 3 bits come from the IRQ number
+                self.vector_base = value & 0b1111_1000;
+                self.init = if !self.single {
+                    InitState::Icw3
+                } else if self.needs_icw4 {
+                    InitState::Icw4
+                } else {
+                    InitState::Ready
+                };
+            }
+            InitState::Icw3 => {
+                // TODO: cascading, we only have the one PIC
+                self.init = if self.needs_icw4 {
+                    InitState::Icw4
+                } else {
+                    InitState::Ready
+                };
+            }
+            InitState::Icw4 => {
+                self.auto_eoi = value & 0b0010 != 0;
+                self.init = InitState::Ready;
+            }
+            // OCW1
+            InitState::Ready => self.imr = value,
+        }
+    }
+}
+
+impl IoDevice for Pic {
+    fn read_port(&mut self, port: u16) -> u8 {
+        if port & 1 == 1 {
+            return self.imr;
+        }
+
+        if self.poll {
+            // a poll read acts like an INTA, bit 7 says whether there was anything
+            self.poll = false;
+            return match self.acknowledge() {
+                Some(vector) => 0b1000_0000 | (vector - self.vector_base),
+                None => 0,
+            };
+        }
+
+        if self.read_isr {
+            self.isr
+        } else {
+            self.irr
+        }
+    }
+
+    fn write_port(&mut self, port: u16, value: u8) {
+        if port & 1 == 1 {
+            self.write_data(value);
+        } else {
+            self.write_command(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_priority_mask_and_eoi() {
+        let mut pic = Pic::new();
+
+        // the PC init sequence - edge triggered, single, ICW4, vectors at 08h, 8086 mode
+        pic.write_port(0x20, 0x13);
+        pic.write_port(0x21, 0x08);
+        pic.write_port(0x21, 0x01);
+        // mask IRQ1
+        pic.write_port(0x21, 0b0000_0010);
+
+        pic.set_irq(1, true);
+        assert!(!pic.interrupt_pending());
+
+        pic.set_irq(3, true);
+        pic.set_irq(5, true);
+        assert_eq!(pic.acknowledge(), Some(0x0b));
+
+        // IRQ5 is lower priority than the in service IRQ3, IRQ0 isn't
+        assert!(!pic.interrupt_pending());
+        pic.set_irq(0, true);
+        assert_eq!(pic.acknowledge(), Some(0x08));
+
+        // non-specific EOI clears the highest priority in service IRQ
+        pic.write_port(0x20, 0x20);
+        assert_eq!(pic.isr(), 0b0000_1000);
+        pic.write_port(0x20, 0x20);
+        assert_eq!(pic.acknowledge(), Some(0x0d));
+
+        // unmasking lets the latched IRQ1 edge through, it outranks the in service IRQ5
+        pic.write_port(0x21, 0);
+        assert_eq!(pic.acknowledge(), Some(0x09));
+
+        // specific EOI for IRQ5
+        pic.write_port(0x20, 0x65);
+        assert_eq!(pic.isr(), 0b0000_0010);
+    }
+}
@@ -0,0 +1,504 @@
+use log::debug;
+
+use crate::{io::IoDevice, pic::IrqLine};
+
+/// Where the PC puts the PIT
+pub const PIT_PORTS: std::ops::RangeInclusive<u16> = 0x40..=0x43;
+/// The PIT runs off 1.193182 MHz, a quarter of the 4.77 MHz CPU clock on the PC
+pub const CPU_CLOCKS_PER_PIT_CLOCK: u32 = 4;
+/// 8088 clock on the PC, 14.31818 MHz / 3
+pub const PC_CPU_CLOCK_HZ: u64 = 4_772_727;
+
+/// How the counter is read and written through its port
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Access {
+    Low,
+    High,
+    LowHigh,
+}
+
+#[derive(Debug)]
+struct Channel {
+    mode: u8,
+    access: Access,
+    bcd: bool,
+    /// Count register, 0 means the max count (65536, or 10000 in BCD)
+    reload: u16,
+    /// Counting element, kept in binary whatever mode we're in
+    count: u32,
+    output: bool,
+    gate: bool,
+    /// Set if a new count has been written that the counting element should pick up on the next clock
+    load_pending: bool,
+    /// Set on a rising edge of the gate
+    triggered: bool,
+    counting: bool,
+    /// Modes 4 and 5 only strobe once per count
+    strobed: bool,
+    latch: Option<u16>,
+    /// For LSB then MSB access, whether the next byte written/read is the MSB
+    write_high: bool,
+    read_high: bool,
+    /// LSB of a count being written in LSB/MSB mode
+    write_low: u8,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            mode: 0,
+            access: Access::LowHigh,
+            bcd: false,
+            reload: 0,
+            count: 0,
+            output: true,
+            gate: true,
+            load_pending: false,
+            triggered: false,
+            counting: false,
+            strobed: false,
+            latch: None,
+            write_high: false,
+            read_high: false,
+            write_low: 0,
+        }
+    }
+
+    fn reload_value(&self) -> u32 {
+        match (self.reload, self.bcd) {
+            (0, false) => 0x10000,
+            (0, true) => 10000,
+            (reload, true) => from_bcd(reload),
+            (reload, false) => reload as u32,
+        }
+    }
+
+    fn max_count(&self) -> u32 {
+        if self.bcd {
+            10000
+        } else {
+            0x10000
+        }
+    }
+
+    /// Value of the counting element as the CPU would read it
+    fn current_count(&self) -> u16 {
+        // TODO: mode 3 really counts down by 2 from the reload value each half cycle, we count half cycle clocks
+        let count = if self.mode == 3 {
+            (self.count * 2) % self.max_count()
+        } else {
+            self.count % self.max_count()
+        };
+
+        if self.bcd {
+            to_bcd(count)
+        } else {
+            count as u16
+        }
+    }
+
+    fn decrement(&mut self) {
+        self.count = match self.count {
+            0 => self.max_count() - 1,
+            count => count - 1,
+        };
+    }
+
+    fn write_control(&mut self, access: Access, mode: u8, bcd: bool) {
+        self.access = access;
+        // modes 6 and 7 are the same as 2 and 3
+        self.mode = if mode >= 6 { mode - 4 } else { mode };
+        self.bcd = bcd;
+        self.latch = None;
+        self.write_high = false;
+        self.read_high = false;
+        self.load_pending = false;
+        self.counting = false;
+        self.output = self.mode != 0;
+    }
+
+    fn write_count(&mut self, value: u8) {
+        let reload = match self.access {
+            Access::Low => value as u16,
+            Access::High => (value as u16) << 8,
+            Access::LowHigh if !self.write_high => {
+                self.write_low = value;
+                self.write_high = true;
+                // writing the first byte in mode 0 stops the count
+                if self.mode == 0 {
+                    self.counting = false;
+                    self.output = false;
+                }
+                return;
+            }
+            Access::LowHigh => {
+                self.write_high = false;
+                u16::from_le_bytes([self.write_low, value])
+            }
+        };
+
+        self.reload = reload;
+        match self.mode {
+            0 => {
+                self.output = false;
+                self.load_pending = true;
+            }
+            4 => self.load_pending = true,
+            // a new count in modes 2 and 3 doesn't take effect until the next reload
+            2 | 3 if !self.counting => self.load_pending = true,
+            // modes 1 and 5 wait for the gate
+            _ => (),
+        }
+    }
+
+    fn read_count(&mut self) -> u8 {
+        let value = self.latch.unwrap_or_else(|| self.current_count());
+        let [low, high] = value.to_le_bytes();
+
+        match self.access {
+            Access::Low => {
+                self.latch = None;
+                low
+            }
+            Access::High => {
+                self.latch = None;
+                high
+            }
+            Access::LowHigh if !self.read_high => {
+                self.read_high = true;
+                low
+            }
+            Access::LowHigh => {
+                self.read_high = false;
+                self.latch = None;
+                high
+            }
+        }
+    }
+
+    fn set_gate(&mut self, gate: bool) {
+        if gate && !self.gate {
+            self.triggered = true;
+        }
+        // modes 2 and 3 force the output high while the gate is low
+        if !gate && (self.mode == 2 || self.mode == 3) {
+            self.output = true;
+        }
+        self.gate = gate;
+    }
+
+    /// One PIT clock
+    fn clock(&mut self) {
+        let triggered = std::mem::take(&mut self.triggered);
+
+        match self.mode {
+            // interrupt on terminal count
+            0 => {
+                if self.load_pending {
+                    self.load_pending = false;
+                    self.count = self.reload_value();
+                    self.counting = true;
+                } else if self.counting && self.gate {
+                    self.decrement();
+                    if self.count == 0 {
+                        self.output = true;
+                    }
+                }
+            }
+            // hardware retriggerable one-shot, the gate doesn't stop the count
+            1 => {
+                if triggered {
+                    self.count = self.reload_value();
+                    self.counting = true;
+                    self.output = false;
+                } else if self.counting {
+                    self.decrement();
+                    if self.count == 0 {
+                        self.output = true;
+                    }
+                }
+            }
+            // rate generator - low for one clock every N
+            2 => {
+                if self.load_pending || triggered {
+                    self.load_pending = false;
+                    self.count = self.reload_value();
+                    self.counting = true;
+                    self.output = true;
+                } else if self.counting && self.gate {
+                    if self.count == 1 {
+                        self.count = self.reload_value();
+                        self.output = true;
+                    } else {
+                        self.decrement();
+                        if self.count == 1 {
+                            self.output = false;
+                        }
+                    }
+                }
+            }
+            // square wave - high for (N + 1) / 2 clocks then low for N / 2, so we count half cycles
+            3 => {
+                if self.load_pending || triggered {
+                    self.load_pending = false;
+                    self.output = true;
+                    self.count = self.reload_value().div_ceil(2);
+                    self.counting = true;
+                } else if self.counting && self.gate {
+                    self.count -= 1;
+                    if self.count == 0 {
+                        self.output = !self.output;
+                        let reload = self.reload_value();
+                        self.count = if self.output {
+                            reload.div_ceil(2)
+                        } else {
+                            (reload / 2).max(1)
+                        };
+                    }
+                }
+            }
+            // software (4) or hardware (5) triggered strobe - low for one clock at terminal count
+            _ => {
+                let start = if self.mode == 4 {
+                    std::mem::take(&mut self.load_pending)
+                } else {
+                    triggered
+                };
+
+                if !self.output {
+                    self.output = true;
+                }
+
+                if start {
+                    self.count = self.reload_value();
+                    self.counting = true;
+                    self.strobed = false;
+                } else if self.counting && (self.mode == 5 || self.gate) {
+                    self.decrement();
+                    if self.count == 0 && !self.strobed {
+                        self.output = false;
+                        self.strobed = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 8253 programmable interval timer. On the PC channel 0 drives IRQ0, channel 1 does DRAM refresh and channel 2
+/// feeds the speaker
+#[derive(Debug)]
+pub struct Pit {
+    channels: [Channel; 3],
+    irq0: Option<IrqLine>,
+    /// CPU clocks that haven't added up to a PIT clock yet
+    remainder: u32,
+}
+
+impl Default for Pit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pit {
+    pub fn new() -> Self {
+        Self {
+            channels: [Channel::new(), Channel::new(), Channel::new()],
+            irq0: None,
+            remainder: 0,
+        }
+    }
+
+    /// Connect channel 0's output to an IRQ line
+    pub fn with_irq0(mut self, irq0: IrqLine) -> Self {
+        self.irq0 = Some(irq0);
+        self
+    }
+
+    pub fn output(&self, channel: usize) -> bool {
+        self.channels[channel].output
+    }
+
+    pub fn gate(&self, channel: usize) -> bool {
+        self.channels[channel].gate
+    }
+
+    pub fn set_gate(&mut self, channel: usize, gate: bool) {
+        self.channels[channel].set_gate(gate);
+    }
+
+    /// Advance every channel by one PIT clock
+    pub fn clock(&mut self) {
+        let output0 = self.channels[0].output;
+
+        for channel in self.channels.iter_mut() {
+            channel.clock();
+        }
+
+        self.update_irq0(output0);
+    }
+
+    /// Pass a change in channel 0's output on to IRQ0
+    fn update_irq0(&self, previous: bool) {
+        if self.channels[0].output != previous {
+            if let Some(irq0) = &self.irq0 {
+                irq0.set(self.channels[0].output);
+            }
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        let channel = (value >> 6) as usize;
+        if channel == 3 {
+            // read-back command on the 8254, illegal on the 8253
+            debug!("ignoring pit control word 0x{:02x}", value);
+            return;
+        }
+
+        let channel = &mut self.channels[channel];
+        let access = match (value >> 4) & 0b11 {
+            0b00 => {
+                // counter latch command, a second latch before the first is read is ignored
+                if channel.latch.is_none() {
+                    channel.latch = Some(channel.current_count());
+                }
+                return;
+            }
+            0b01 => Access::Low,
+            0b10 => Access::High,
+            _ => Access::LowHigh,
+        };
+
+        channel.write_control(access, (value >> 1) & 0b111, value & 1 != 0);
+    }
+}
+
+impl IoDevice for Pit {
+    fn read_port(&mut self, port: u16) -> u8 {
+        match port & 0b11 {
+            // the control register can't be read
+            0b11 => 0xff,
+            channel => self.channels[channel as usize].read_count(),
+        }
+    }
+
+    fn write_port(&mut self, port: u16, value: u8) {
+        let output0 = self.channels[0].output;
+
+        match port & 0b11 {
+            0b11 => self.write_control(value),
+            channel => self.channels[channel as usize].write_count(value),
+        }
+
+        self.update_irq0(output0);
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.remainder += cycles;
+        while self.remainder >= CPU_CLOCKS_PER_PIT_CLOCK {
+            self.remainder -= CPU_CLOCKS_PER_PIT_CLOCK;
+            self.clock();
+        }
+    }
+}
+
+fn from_bcd(value: u16) -> u32 {
+    (0..4).rev().fold(0, |acc, digit| {
+        acc * 10 + ((value >> (digit * 4)) & 0xf) as u32
+    })
+}
+
+fn to_bcd(value: u32) -> u16 {
+    (0..4).fold(0, |acc, digit| {
+        acc | ((((value / 10u32.pow(digit)) % 10) as u16) << (digit * 4))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        io::IoBus,
+        pic::{Pic, PIC_PORTS},
+    };
+
+    type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+    #[test]
+    fn test_modes() {
+        let mut pit = Pit::new();
+
+        // channel 2, LSB only, mode 0 (terminal count)
+        pit.write_port(0x43, 0b1001_0000);
+        pit.write_port(0x42, 3);
+        assert!(!pit.output(2));
+
+        // one clock to load, then 3 to count down
+        for _ in 0..3 {
+            pit.clock();
+            assert!(!pit.output(2));
+        }
+        pit.clock();
+        assert!(pit.output(2));
+
+        // channel 1, LSB/MSB, mode 3 with an odd count - high for 3 clocks, low for 2
+        pit.write_port(0x43, 0b0111_0110);
+        pit.write_port(0x41, 5);
+        pit.write_port(0x41, 0);
+        pit.clock();
+
+        let mut outputs = Vec::new();
+        for _ in 0..10 {
+            outputs.push(pit.output(1));
+            pit.clock();
+        }
+        assert_eq!(
+            outputs,
+            [true, true, true, false, false, true, true, true, false, false]
+        );
+
+        // latching channel 0 while in BCD
+        pit.write_port(0x43, 0b0011_0001);
+        pit.write_port(0x40, 0x34);
+        pit.write_port(0x40, 0x12);
+        pit.clock();
+        pit.clock();
+        pit.write_port(0x43, 0b0000_0000);
+        pit.clock();
+        assert_eq!(pit.read_port(0x40), 0x33);
+        assert_eq!(pit.read_port(0x40), 0x12);
+    }
+
+    #[test]
+    fn test_irq0_timer_ticks() -> Result<()> {
+        let pic = Pic::new().shared();
+        let mut bus = IoBus::new();
+        bus.register(&[PIC_PORTS], Box::new(pic.clone()))?;
+        bus.register(
+            &[PIT_PORTS],
+            Box::new(Pit::new().with_irq0(IrqLine::new(pic.clone(), 0))),
+        )?;
+
+        // channel 0, rate generator, 100 PIT clocks per tick
+        bus.write_byte(0x43, 0b0011_0100);
+        bus.write_byte(0x40, 100);
+        bus.write_byte(0x40, 0);
+
+        let mut ticks = Vec::new();
+        for cycle in (4..=1300).step_by(4) {
+            bus.tick(4);
+
+            if pic.borrow().interrupt_pending() {
+                assert_eq!(pic.borrow_mut().acknowledge(), Some(0x08));
+                ticks.push(cycle);
+                // EOI
+                bus.write_byte(0x20, 0x20);
+            }
+        }
+
+        // loading takes a PIT clock, then the output rises every 100 PIT clocks
+        assert_eq!(ticks, [404, 804, 1204]);
+        Ok(())
+    }
+}
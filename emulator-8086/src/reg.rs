@@ -127,3 +127,49 @@ impl Register {
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentRegister {
+    ES,
+    CS,
+    SS,
+    DS,
+}
+
+impl fmt::Display for SegmentRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                SegmentRegister::ES => "es",
+                SegmentRegister::CS => "cs",
+                SegmentRegister::SS => "ss",
+                SegmentRegister::DS => "ds",
+            }
+        )
+    }
+}
+
+impl SegmentRegister {
+    /// The 2 bit SR field, used by segment register moves and push/pop
+    pub fn from_sr(value: u8) -> Self {
+        match value & 0b11 {
+            0b00 => SegmentRegister::ES,
+            0b01 => SegmentRegister::CS,
+            0b10 => SegmentRegister::SS,
+            _ => SegmentRegister::DS,
+        }
+    }
+
+    /// Segment override prefix byte, e.g. 0x26 for `es:`
+    pub fn from_prefix(value: u8) -> Option<Self> {
+        match value {
+            0x26 => Some(SegmentRegister::ES),
+            0x2e => Some(SegmentRegister::CS),
+            0x36 => Some(SegmentRegister::SS),
+            0x3e => Some(SegmentRegister::DS),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::{cell::RefCell, path::Path, rc::Rc};
+
+use crate::{
+    io::IoDevice,
+    pit::{Pit, PC_CPU_CLOCK_HZ},
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Port B of the PPI (system control port B on the AT)
+pub const SPEAKER_PORT: u16 = 0x61;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// Bit 0 gates PIT channel 2, bit 1 lets its output through to the speaker
+const TIMER_2_GATE: u8 = 0b01;
+const SPEAKER_DATA: u8 = 0b10;
+/// Channel 2's output reads back on bit 5
+const TIMER_2_OUTPUT: u8 = 0b10_0000;
+
+const AMPLITUDE: i16 = 8000;
+
+/// PC speaker driven by PIT channel 2, sampled against emulated CPU clocks rather than wall time
+#[derive(Debug)]
+pub struct Speaker {
+    pit: Rc<RefCell<Pit>>,
+    port_b: u8,
+    sample_rate: u32,
+    cpu_clock_hz: u64,
+    /// Emulated time towards the next sample, in units of 1 / (cpu_clock_hz * sample_rate) seconds
+    phase: u64,
+    samples: Vec<i16>,
+}
+
+impl Speaker {
+    pub fn new(pit: Rc<RefCell<Pit>>) -> Self {
+        Self {
+            pit,
+            port_b: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            cpu_clock_hz: PC_CPU_CLOCK_HZ,
+            phase: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn with_cpu_clock(mut self, cpu_clock_hz: u64) -> Self {
+        self.cpu_clock_hz = cpu_clock_hz;
+        self
+    }
+
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    /// Where the speaker cone is right now
+    fn level(&self) -> i16 {
+        if self.port_b & SPEAKER_DATA == 0 {
+            return 0;
+        }
+
+        // TODO: with the gate off the output is held wherever it was, the real speaker would drift back to rest
+        if self.pit.borrow().output(2) {
+            AMPLITUDE
+        } else {
+            -AMPLITUDE
+        }
+    }
+
+    /// 16 bit mono PCM WAV of everything captured so far
+    pub fn to_wav(&self) -> Vec<u8> {
+        let data_len = (self.samples.len() * 2) as u32;
+        let mut wav = Vec::with_capacity(44 + data_len as usize);
+
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, mono
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&self.sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(self.sample_rate * 2).to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        for sample in &self.samples {
+            wav.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        wav
+    }
+
+    pub fn write_wav<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(std::fs::write(path, self.to_wav())?)
+    }
+}
+
+impl IoDevice for Speaker {
+    fn read_port(&mut self, _port: u16) -> u8 {
+        let output = if self.pit.borrow().output(2) {
+            TIMER_2_OUTPUT
+        } else {
+            0
+        };
+        (self.port_b & !TIMER_2_OUTPUT) | output
+    }
+
+    fn write_port(&mut self, _port: u16, value: u8) {
+        self.port_b = value;
+        self.pit.borrow_mut().set_gate(2, value & TIMER_2_GATE != 0);
+    }
+
+    /// Samples are taken at the end of each tick, so this is only as fine grained as the caller's ticks
+    fn tick(&mut self, cycles: u32) {
+        self.phase += cycles as u64 * self.sample_rate as u64;
+
+        let level = self.level();
+        while self.phase >= self.cpu_clock_hz {
+            self.phase -= self.cpu_clock_hz;
+            self.samples.push(level);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{io::IoBus, pit::PIT_PORTS};
+
+    #[test]
+    fn test_tone_frequency() -> Result<()> {
+        let pit = Rc::new(RefCell::new(Pit::new()));
+        let speaker = Rc::new(RefCell::new(Speaker::new(pit.clone())));
+
+        let mut bus = IoBus::new();
+        bus.register(&[PIT_PORTS], Box::new(pit))?;
+        bus.register(&[SPEAKER_PORT..=SPEAKER_PORT], Box::new(speaker.clone()))?;
+
+        // channel 2 square wave at 1193182 / 1193 = ~1000 Hz, then gate it through to the speaker
+        bus.write_byte(0x43, 0b1011_0110);
+        bus.write_byte(0x42, (1193 & 0xff) as u8);
+        bus.write_byte(0x42, (1193 >> 8) as u8);
+        bus.write_byte(SPEAKER_PORT, TIMER_2_GATE | SPEAKER_DATA);
+
+        // a tenth of a second of emulated time
+        for _ in 0..PC_CPU_CLOCK_HZ / 10 / 4 {
+            bus.tick(4);
+        }
+
+        let speaker = speaker.borrow();
+        let samples = speaker.samples();
+        assert_eq!(samples.len(), 4409);
+
+        let rising_edges = samples.windows(2).filter(|w| w[0] < 0 && w[1] > 0).count();
+        assert!(
+            (99..=101).contains(&rising_edges),
+            "{} cycles",
+            rising_edges
+        );
+
+        let wav = speaker.to_wav();
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(wav.len(), 44 + 4409 * 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+//! Helpers shared by the line based text formats - timing traces, key scripts and memory maps
+
+/// The non-blank lines of `s` with `#` comments stripped, as (line number from 1, raw line, contents)
+pub fn lines(s: &str) -> impl Iterator<Item = (usize, &str, &str)> {
+    s.lines().enumerate().filter_map(|(i, raw_line)| {
+        let line = raw_line.split('#').next().unwrap_or_default().trim();
+        (!line.is_empty()).then_some((i + 1, raw_line, line))
+    })
+}
+
+/// Hex with a 0x prefix, otherwise decimal. `None` if it doesn't parse or doesn't fit in `T`
+pub fn parse_number<T: TryFrom<u32>>(s: &str) -> Option<T> {
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    T::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        let parsed: Vec<_> = lines("# header\n\n  ram 0x10 20 # trailing\n").collect();
+        assert_eq!(parsed, [(3, "  ram 0x10 20 # trailing", "ram 0x10 20")]);
+
+        assert_eq!(parse_number::<u16>("0x10"), Some(0x10));
+        assert_eq!(parse_number::<u16>("20"), Some(20));
+        assert_eq!(parse_number::<u16>("0x10000"), None);
+        assert_eq!(parse_number::<u32>("10h"), None);
+    }
+}
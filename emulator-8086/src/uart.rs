@@ -0,0 +1,471 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver},
+};
+
+use log::error;
+
+use crate::{io::IoDevice, pic::IrqLine, pit::PC_CPU_CLOCK_HZ};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// COM1 ports, it raises IRQ4
+pub const COM1_PORTS: std::ops::RangeInclusive<u16> = 0x3f8..=0x3ff;
+pub const COM1_IRQ: u8 = 4;
+
+/// The divisor latch divides this down to the baud rate
+const UART_CLOCK_BAUD: u32 = 115200;
+/// Start bit, 8 data bits, stop bit
+const BITS_PER_CHARACTER: u64 = 10;
+
+// interrupt enable register
+const IER_RECEIVED_DATA: u8 = 0b0001;
+const IER_THR_EMPTY: u8 = 0b0010;
+const IER_LINE_STATUS: u8 = 0b0100;
+const IER_MODEM_STATUS: u8 = 0b1000;
+
+// line status register
+const LSR_DATA_READY: u8 = 0b0000_0001;
+const LSR_OVERRUN: u8 = 0b0000_0010;
+const LSR_THR_EMPTY: u8 = 0b0010_0000;
+const LSR_TRANSMITTER_EMPTY: u8 = 0b0100_0000;
+
+// modem control register
+const MCR_OUT2: u8 = 0b0_1000;
+const MCR_LOOPBACK: u8 = 0b1_0000;
+
+const LCR_DLAB: u8 = 0b1000_0000;
+
+/// The other end of the serial line
+pub trait SerialBackend {
+    /// A byte from the other end if there is one, this mustn't block
+    fn receive(&mut self) -> Option<u8>;
+
+    fn transmit(&mut self, byte: u8);
+}
+
+/// Host stdin/stdout. Stdin is read on a separate thread so the emulator never blocks on it
+#[derive(Debug)]
+pub struct StdioBackend {
+    input: Receiver<u8>,
+}
+
+impl Default for StdioBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StdioBackend {
+    pub fn new() -> Self {
+        let (sender, input) = mpsc::channel();
+        std::thread::spawn(move || {
+            for byte in std::io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) if sender.send(byte).is_ok() => (),
+                    _ => break,
+                }
+            }
+        });
+        Self { input }
+    }
+}
+
+impl SerialBackend for StdioBackend {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        let mut stdout = std::io::stdout().lock();
+        if let Err(e) = stdout.write_all(&[byte]).and_then(|_| stdout.flush()) {
+            error!("failed to write serial output: {}", e);
+        }
+    }
+}
+
+/// Logs everything transmitted to a file, nothing is ever received
+#[derive(Debug)]
+pub struct FileBackend {
+    file: File,
+}
+
+impl FileBackend {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            file: File::create(path)?,
+        })
+    }
+}
+
+impl SerialBackend for FileBackend {
+    fn receive(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if let Err(e) = self.file.write_all(&[byte]) {
+            error!("failed to write serial log: {}", e);
+        }
+    }
+}
+
+/// A Unix pseudo-terminal, connect a terminal program or script to the path from `slave_path`
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct PtyBackend {
+    master: File,
+    slave_path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl PtyBackend {
+    pub fn open() -> Result<Self> {
+        use std::{ffi::CStr, os::fd::FromRawFd};
+
+        // SAFETY: plain libc calls, the fd is checked before use and handed to File which owns it from then on
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let master = File::from_raw_fd(fd);
+
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            // raw mode so bytes go through untouched, no echo or line buffering
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(fd, &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                libc::tcsetattr(fd, libc::TCSANOW, &termios);
+            }
+
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let slave_path = CStr::from_ptr(name).to_str()?.into();
+
+            Ok(Self { master, slave_path })
+        }
+    }
+
+    pub fn slave_path(&self) -> &Path {
+        &self.slave_path
+    }
+}
+
+#[cfg(unix)]
+impl SerialBackend for PtyBackend {
+    fn receive(&mut self) -> Option<u8> {
+        let mut byte = [0u8; 1];
+        // WouldBlock just means nothing's been typed, and EIO means nobody has the slave open yet
+        match self.master.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if let Err(e) = self.master.write_all(&[byte]) {
+            error!("failed to write to pty: {}", e);
+        }
+    }
+}
+
+/// 8250/16450 UART. Transmits go straight out to the backend, received bytes are polled from it at the
+/// programmed baud rate in emulated time
+pub struct Uart {
+    backend: Box<dyn SerialBackend>,
+    irq: Option<IrqLine>,
+    divisor: u16,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    scratch: u8,
+    rbr: u8,
+    /// Set when THR empties, cleared by reading IIR or writing THR
+    thr_empty_pending: bool,
+    /// Modem status delta bits (low nibble of MSR)
+    msr_delta: u8,
+    cpu_clock_hz: u64,
+    /// CPU clocks into the current character time
+    clocks: u64,
+}
+
+impl std::fmt::Debug for Uart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Uart")
+            .field("divisor", &self.divisor)
+            .field("ier", &self.ier)
+            .field("lcr", &self.lcr)
+            .field("mcr", &self.mcr)
+            .field("lsr", &self.lsr)
+            .finish()
+    }
+}
+
+impl Uart {
+    pub fn new(backend: Box<dyn SerialBackend>) -> Self {
+        Self {
+            backend,
+            irq: None,
+            // 9600 baud
+            divisor: 12,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY,
+            scratch: 0,
+            rbr: 0,
+            thr_empty_pending: false,
+            msr_delta: 0,
+            cpu_clock_hz: PC_CPU_CLOCK_HZ,
+            clocks: 0,
+        }
+    }
+
+    pub fn with_irq(mut self, irq: IrqLine) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    pub fn with_cpu_clock(mut self, cpu_clock_hz: u64) -> Self {
+        self.cpu_clock_hz = cpu_clock_hz;
+        self
+    }
+
+    pub fn baud(&self) -> u32 {
+        UART_CLOCK_BAUD / self.divisor.max(1) as u32
+    }
+
+    fn loopback(&self) -> bool {
+        self.mcr & MCR_LOOPBACK != 0
+    }
+
+    fn receive_byte(&mut self, byte: u8) {
+        if self.lsr & LSR_DATA_READY != 0 {
+            self.lsr |= LSR_OVERRUN;
+        }
+        self.rbr = byte;
+        self.lsr |= LSR_DATA_READY;
+    }
+
+    fn transmit_byte(&mut self, byte: u8) {
+        if self.loopback() {
+            self.receive_byte(byte);
+        } else {
+            self.backend.transmit(byte);
+        }
+        // we send instantly, so THR is straight back to empty
+        self.lsr |= LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY;
+        self.thr_empty_pending = true;
+    }
+
+    fn modem_status(&self) -> u8 {
+        let lines = if self.loopback() {
+            // DTR -> DSR, RTS -> CTS, OUT1 -> RI, OUT2 -> DCD
+            let mcr = self.mcr;
+            ((mcr & 0b0010) << 3)
+                | ((mcr & 0b0001) << 5)
+                | ((mcr & 0b0100) << 4)
+                | ((mcr & 0b1000) << 4)
+        } else {
+            // CTS, DSR and DCD, there's always someone there
+            0b1011_0000
+        };
+        lines | self.msr_delta
+    }
+
+    /// Highest priority pending interrupt, in IIR encoding
+    fn interrupt_id(&self) -> Option<u8> {
+        if self.ier & IER_LINE_STATUS != 0 && self.lsr & LSR_OVERRUN != 0 {
+            Some(0b110)
+        } else if self.ier & IER_RECEIVED_DATA != 0 && self.lsr & LSR_DATA_READY != 0 {
+            Some(0b100)
+        } else if self.ier & IER_THR_EMPTY != 0 && self.thr_empty_pending {
+            Some(0b010)
+        } else if self.ier & IER_MODEM_STATUS != 0 && self.msr_delta != 0 {
+            Some(0b000)
+        } else {
+            None
+        }
+    }
+
+    /// On the PC OUT2 has to be set for the interrupt to get through to the PIC
+    fn update_irq(&self) {
+        if let Some(irq) = &self.irq {
+            irq.set(self.mcr & MCR_OUT2 != 0 && self.interrupt_id().is_some());
+        }
+    }
+}
+
+impl IoDevice for Uart {
+    fn read_port(&mut self, port: u16) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        let value = match (port & 0b111, dlab) {
+            (0, false) => {
+                self.lsr &= !LSR_DATA_READY;
+                self.rbr
+            }
+            (0, true) => self.divisor.to_le_bytes()[0],
+            (1, false) => self.ier,
+            (1, true) => self.divisor.to_le_bytes()[1],
+            (2, _) => match self.interrupt_id() {
+                Some(id) => {
+                    // reading IIR is what acknowledges a THR empty interrupt
+                    if id == 0b010 {
+                        self.thr_empty_pending = false;
+                    }
+                    id
+                }
+                None => 0b001,
+            },
+            (3, _) => self.lcr,
+            (4, _) => self.mcr,
+            (5, _) => {
+                let lsr = self.lsr;
+                self.lsr &= !LSR_OVERRUN;
+                lsr
+            }
+            (6, _) => {
+                let msr = self.modem_status();
+                self.msr_delta = 0;
+                msr
+            }
+            _ => self.scratch,
+        };
+
+        self.update_irq();
+        value
+    }
+
+    fn write_port(&mut self, port: u16, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match (port & 0b111, dlab) {
+            (0, false) => self.transmit_byte(value),
+            (0, true) => self.divisor = (self.divisor & 0xff00) | value as u16,
+            (1, false) => {
+                // turning on the THR empty interrupt with THR already empty fires it straight away
+                if value & IER_THR_EMPTY != 0
+                    && self.ier & IER_THR_EMPTY == 0
+                    && self.lsr & LSR_THR_EMPTY != 0
+                {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & 0b1111;
+            }
+            (1, true) => self.divisor = (self.divisor & 0x00ff) | ((value as u16) << 8),
+            (3, _) => self.lcr = value,
+            (4, _) => {
+                self.mcr = value & 0b1_1111;
+                if self.loopback() {
+                    self.msr_delta |= 0b0001;
+                }
+            }
+            (7, _) => self.scratch = value,
+            // IIR, LSR and MSR are read only
+            _ => (),
+        }
+
+        self.update_irq();
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.clocks += cycles as u64;
+
+        // keep the leftover clocks, so the rate doesn't depend on how big each tick is
+        let per_character = self.cpu_clock_hz * BITS_PER_CHARACTER / self.baud() as u64;
+        while self.clocks >= per_character {
+            self.clocks -= per_character;
+
+            // leave anything else waiting in the backend until the program has read what it's got
+            if !self.loopback() && self.lsr & LSR_DATA_READY == 0 {
+                if let Some(byte) = self.backend.receive() {
+                    self.receive_byte(byte);
+                    self.update_irq();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    use super::*;
+    use crate::pic::Pic;
+
+    /// Plays back canned input and keeps whatever gets sent
+    struct ScriptedBackend {
+        input: VecDeque<u8>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl SerialBackend for ScriptedBackend {
+        fn receive(&mut self) -> Option<u8> {
+            self.input.pop_front()
+        }
+
+        fn transmit(&mut self, byte: u8) {
+            self.output.borrow_mut().push(byte);
+        }
+    }
+
+    #[test]
+    fn test_uart() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let backend = ScriptedBackend {
+            input: b"ok".iter().copied().collect(),
+            output: output.clone(),
+        };
+        let pic = Pic::new().shared();
+        let mut uart = Uart::new(Box::new(backend)).with_irq(IrqLine::new(pic.clone(), COM1_IRQ));
+
+        // 115200 baud, 8N1, OUT2 on, received data interrupts
+        uart.write_port(0x3fb, LCR_DLAB | 0b11);
+        uart.write_port(0x3f8, 1);
+        uart.write_port(0x3f9, 0);
+        uart.write_port(0x3fb, 0b11);
+        uart.write_port(0x3fc, MCR_OUT2);
+        uart.write_port(0x3f9, IER_RECEIVED_DATA);
+        assert_eq!(uart.baud(), 115200);
+
+        for byte in b"hi" {
+            uart.write_port(0x3f8, *byte);
+        }
+        assert_eq!(output.borrow().as_slice(), b"hi");
+
+        // a character takes ~414 CPU clocks at 115200 baud
+        uart.tick(400);
+        assert_eq!(uart.read_port(0x3fd) & LSR_DATA_READY, 0);
+        uart.tick(20);
+        assert_eq!(uart.read_port(0x3fd) & LSR_DATA_READY, LSR_DATA_READY);
+        assert_eq!(uart.read_port(0x3fa), 0b100);
+        assert_eq!(pic.borrow_mut().acknowledge(), Some(0x0c));
+        assert_eq!(uart.read_port(0x3f8), b'o');
+        assert_eq!(uart.read_port(0x3fa), 0b001);
+        // the 6 clocks over from the first character count towards the next one
+        uart.tick(408);
+        assert_eq!(uart.read_port(0x3f8), b'k');
+
+        // loopback sends THR straight back to RBR
+        uart.write_port(0x3fc, MCR_LOOPBACK);
+        uart.write_port(0x3f8, b'!');
+        assert_eq!(uart.read_port(0x3f8), b'!');
+        assert_eq!(output.borrow().as_slice(), b"hi");
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    cell::RefCell,
+    fmt::Write,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    io::IoDevice,
+    memory::{MemoryDevice, MemoryMap},
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Physical address of the CGA text buffer (B800:0000)
+pub const CGA_TEXT_ADDRESS: usize = 0xb8000;
+/// Physical address of the MDA text buffer (B000:0000)
+pub const MDA_TEXT_ADDRESS: usize = 0xb0000;
+/// The MDA only has 4K, enough for one text page
+pub const MDA_RAM_SIZE: usize = 0x1000;
+/// Each card decodes a 32K window and repeats its RAM through it
+const VIDEO_WINDOW_SIZE: usize = 0x8000;
+
+pub const TEXT_COLUMNS: usize = 80;
+pub const TEXT_ROWS: usize = 25;
+
+/// CGA mode control, colour select and status registers
+pub const CGA_PORTS: std::ops::RangeInclusive<u16> = 0x3d8..=0x3da;
+/// Size of the CGA graphics buffer, two interlaced 8K banks
+pub const CGA_GRAPHICS_SIZE: usize = 0x4000;
+/// Odd scanlines live in the second bank
+const CGA_ODD_BANK: usize = 0x2000;
+const CGA_BYTES_PER_LINE: usize = 80;
+const CGA_LINES: usize = 200;
+
+/// Code page 437 glyphs for 0x00-0x1f, NUL shows up as a blank
+const CP437_CONTROL: [char; 32] = [
+    ' ', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', '◄', '↕',
+    '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// Code page 437 glyphs for 0x7f-0xff
+const CP437_HIGH: [char; 129] = [
+    '⌂', 'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', 'É', 'æ',
+    'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', 'á', 'í', 'ó', 'ú', 'ñ',
+    'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', '░', '▒', '▓', '│', '┤', '╡', '╢', '╖',
+    '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩',
+    '╦', '╠', '═', '╬', '╧', '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌',
+    '▐', '▀', 'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', '≡',
+    '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', ' ',
+];
+
+/// CGA colour index (IRGB order) to the matching ANSI colour
+const CGA_TO_ANSI: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+/// The 16 RGBI colours as a monitor shows them, with colour 6 as brown rather than dark yellow
+const CGA_RGB: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xaa],
+    [0x00, 0xaa, 0x00],
+    [0x00, 0xaa, 0xaa],
+    [0xaa, 0x00, 0x00],
+    [0xaa, 0x00, 0xaa],
+    [0xaa, 0x55, 0x00],
+    [0xaa, 0xaa, 0xaa],
+    [0x55, 0x55, 0x55],
+    [0x55, 0x55, 0xff],
+    [0x55, 0xff, 0x55],
+    [0x55, 0xff, 0xff],
+    [0xff, 0x55, 0x55],
+    [0xff, 0x55, 0xff],
+    [0xff, 0xff, 0x55],
+    [0xff, 0xff, 0xff],
+];
+
+/// Map a code page 437 byte to the character it draws as
+pub fn cp437_to_char(byte: u8) -> char {
+    match byte {
+        0x00..=0x1f => CP437_CONTROL[byte as usize],
+        0x20..=0x7e => byte as char,
+        _ => CP437_HIGH[byte as usize - 0x7f],
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextAdapter {
+    Cga,
+    Mda,
+}
+
+impl TextAdapter {
+    pub fn buffer_address(&self) -> usize {
+        match self {
+            Self::Cga => CGA_TEXT_ADDRESS,
+            Self::Mda => MDA_TEXT_ADDRESS,
+        }
+    }
+
+    pub fn ram_size(&self) -> usize {
+        match self {
+            Self::Cga => CGA_GRAPHICS_SIZE,
+            Self::Mda => MDA_RAM_SIZE,
+        }
+    }
+
+    /// SGR parameters for an attribute byte
+    fn sgr(&self, attribute: u8) -> String {
+        let mut params = vec!["0".to_owned()];
+
+        match self {
+            Self::Cga => {
+                let fg = CGA_TO_ANSI[(attribute & 0b111) as usize];
+                let fg_base = if attribute & 0b1000 != 0 { 90 } else { 30 };
+                let bg = CGA_TO_ANSI[((attribute >> 4) & 0b111) as usize];
+                params.push((fg_base + fg).to_string());
+                params.push((40 + bg).to_string());
+            }
+            Self::Mda => {
+                // MDA only knows normal, bright, underline, reverse and invisible
+                if attribute & 0b1000 != 0 {
+                    params.push("1".to_owned());
+                }
+                match attribute & 0x77 {
+                    0x00 => params.push("8".to_owned()),
+                    0x01 => params.push("4".to_owned()),
+                    0x70 => params.push("7".to_owned()),
+                    _ => (),
+                }
+            }
+        }
+
+        // TODO: CGA can be set up to use bit 7 for bright backgrounds instead
+        if attribute & 0x80 != 0 {
+            params.push("5".to_owned());
+        }
+
+        params.join(";")
+    }
+}
+
+/// The RAM on a display card, to map into memory with [`MemoryMap::map_device`]
+#[derive(Debug)]
+pub struct VideoRam {
+    adapter: TextAdapter,
+    data: Vec<u8>,
+}
+
+impl VideoRam {
+    pub fn new(adapter: TextAdapter) -> Self {
+        Self {
+            adapter,
+            data: vec![0; adapter.ram_size()],
+        }
+    }
+
+    /// Wrap up in an `Rc` so the display can still get at it once it's mapped into memory
+    pub fn shared(self) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(self))
+    }
+
+    /// Where the card answers - B8000-BFFFF for the CGA, B0000-B7FFF for the MDA
+    pub fn range(&self) -> RangeInclusive<u32> {
+        let start = self.adapter.buffer_address() as u32;
+        start..=start + VIDEO_WINDOW_SIZE as u32 - 1
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl MemoryDevice for VideoRam {
+    fn read_byte(&mut self, offset: u32) -> u8 {
+        self.data[offset as usize % self.data.len()]
+    }
+
+    fn write_byte(&mut self, offset: u32, value: u8) {
+        let len = self.data.len();
+        self.data[offset as usize % len] = value;
+    }
+}
+
+/// Character and attribute byte for one cell of a text screen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextCell {
+    pub character: u8,
+    pub attribute: u8,
+}
+
+/// Snapshot of an 80x25 text mode screen
+#[derive(Debug, PartialEq, Eq)]
+pub struct TextScreen {
+    adapter: TextAdapter,
+    cells: Vec<TextCell>,
+}
+
+impl TextScreen {
+    /// Read the screen out of the adapter's buffer in memory
+    pub fn from_memory(memory: &mut MemoryMap, adapter: TextAdapter) -> Self {
+        let start = adapter.buffer_address() as u32;
+        Self::from_buffer(
+            &memory.read_bytes(start, TEXT_COLUMNS * TEXT_ROWS * 2),
+            adapter,
+        )
+    }
+
+    /// Read the screen out of just the video buffer, character/attribute pairs. A buffer shorter than the 4000
+    /// bytes of a full screen is padded out with blanks
+    pub fn from_buffer(buffer: &[u8], adapter: TextAdapter) -> Self {
+        let blank = TextCell {
+            character: b' ',
+            attribute: 0x07,
+        };
+        let mut cells: Vec<TextCell> = buffer
+            .chunks_exact(2)
+            .take(TEXT_COLUMNS * TEXT_ROWS)
+            .map(|cell| TextCell {
+                character: cell[0],
+                attribute: cell[1],
+            })
+            .collect();
+        cells.resize(TEXT_COLUMNS * TEXT_ROWS, blank);
+
+        Self { adapter, cells }
+    }
+
+    pub fn cell(&self, row: usize, column: usize) -> TextCell {
+        self.cells[row * TEXT_COLUMNS + column]
+    }
+
+    /// One row as plain text, with trailing blanks trimmed
+    pub fn row(&self, row: usize) -> String {
+        let start = row * TEXT_COLUMNS;
+        self.cells[start..start + TEXT_COLUMNS]
+            .iter()
+            .map(|cell| cp437_to_char(cell.character))
+            .collect::<String>()
+            .trim_end()
+            .to_owned()
+    }
+
+    /// The whole screen as plain text, one line per row
+    pub fn to_text(&self) -> String {
+        (0..TEXT_ROWS)
+            .map(|row| self.row(row))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The whole screen with attributes as ANSI escapes, starting from the top left of the terminal. This can be
+    /// written out repeatedly to render live, or once as a final dump
+    pub fn to_ansi(&self) -> String {
+        let mut out = String::from("\x1b[H");
+
+        for row in self.cells.chunks(TEXT_COLUMNS) {
+            let mut attribute = None;
+            for cell in row {
+                if attribute != Some(cell.attribute) {
+                    attribute = Some(cell.attribute);
+                    // writing to a String can't fail
+                    let _ = write!(out, "\x1b[{}m", self.adapter.sgr(cell.attribute));
+                }
+                out.push(cp437_to_char(cell.character));
+            }
+            out.push_str("\x1b[0m\n");
+        }
+
+        out
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgaGraphicsMode {
+    /// 320x200, 2 bits per pixel
+    Medium,
+    /// 640x200, 1 bit per pixel
+    High,
+}
+
+/// An RGB image
+#[derive(Debug, PartialEq, Eq)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u8; 3]>,
+}
+
+impl Framebuffer {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        self.pixels[y * self.width + x]
+    }
+
+    /// Binary (P6) PPM
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        ppm.extend(self.pixels.iter().flatten());
+        ppm
+    }
+
+    pub fn write_ppm<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(std::fs::write(path, self.to_ppm())?)
+    }
+}
+
+/// Decode the CGA graphics buffer, which should be the full `CGA_GRAPHICS_SIZE` bytes - anything missing off the
+/// end decodes as colour 0. `colour_select` and `mode_control` are the values last written to 3D9h and 3D8h, they
+/// pick the palette
+pub fn decode_cga_graphics(
+    buffer: &[u8],
+    mode: CgaGraphicsMode,
+    colour_select: u8,
+    mode_control: u8,
+) -> Framebuffer {
+    let palette = match mode {
+        CgaGraphicsMode::Medium => {
+            let intensity = if colour_select & 0b1_0000 != 0 { 8 } else { 0 };
+            let colours = if mode_control & 0b100 != 0 {
+                // the "black and white" bit gives cyan/red/white in 320x200
+                [3, 4, 7]
+            } else if colour_select & 0b10_0000 != 0 {
+                [3, 5, 7]
+            } else {
+                [2, 4, 6]
+            };
+            vec![
+                colour_select & 0b1111,
+                colours[0] + intensity,
+                colours[1] + intensity,
+                colours[2] + intensity,
+            ]
+        }
+        CgaGraphicsMode::High => vec![0, colour_select & 0b1111],
+    };
+
+    let bits_per_pixel = match mode {
+        CgaGraphicsMode::Medium => 2,
+        CgaGraphicsMode::High => 1,
+    };
+    let width = CGA_BYTES_PER_LINE * 8 / bits_per_pixel;
+    let mask = (1u8 << bits_per_pixel) - 1;
+
+    let mut pixels = Vec::with_capacity(width * CGA_LINES);
+    for y in 0..CGA_LINES {
+        let bank = if y % 2 == 1 { CGA_ODD_BANK } else { 0 };
+        let line = bank + (y / 2) * CGA_BYTES_PER_LINE;
+
+        for i in line..line + CGA_BYTES_PER_LINE {
+            let byte = buffer.get(i).copied().unwrap_or(0);
+            // leftmost pixel is in the high bits
+            for shift in (0..8).step_by(bits_per_pixel).rev() {
+                let index = (byte >> shift) & mask;
+                pixels.push(CGA_RGB[palette[index as usize] as usize]);
+            }
+        }
+    }
+
+    Framebuffer {
+        width,
+        height: CGA_LINES,
+        pixels,
+    }
+}
+
+/// The CGA registers that decide how the buffer at B800:0000 gets displayed
+#[derive(Debug, Default)]
+pub struct CgaRegisters {
+    mode_control: u8,
+    colour_select: u8,
+    /// Toggled on every status read so retrace polling loops don't hang
+    retrace: bool,
+}
+
+impl CgaRegisters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// None while in text mode
+    pub fn graphics_mode(&self) -> Option<CgaGraphicsMode> {
+        match (
+            self.mode_control & 0b10 != 0,
+            self.mode_control & 0b1_0000 != 0,
+        ) {
+            (false, _) => None,
+            (true, false) => Some(CgaGraphicsMode::Medium),
+            (true, true) => Some(CgaGraphicsMode::High),
+        }
+    }
+
+    /// Decode the current graphics screen out of the buffer at B800:0000
+    pub fn decode(&self, memory: &mut MemoryMap) -> Option<Framebuffer> {
+        let mode = self.graphics_mode()?;
+        let buffer = memory.read_bytes(CGA_TEXT_ADDRESS as u32, CGA_GRAPHICS_SIZE);
+        Some(decode_cga_graphics(
+            &buffer,
+            mode,
+            self.colour_select,
+            self.mode_control,
+        ))
+    }
+}
+
+impl IoDevice for CgaRegisters {
+    fn read_port(&mut self, port: u16) -> u8 {
+        match port {
+            // status - TODO: real retrace timing once there's a simulator clocking us
+            0x3da => {
+                self.retrace = !self.retrace;
+                if self.retrace {
+                    0b1001
+                } else {
+                    0
+                }
+            }
+            _ => 0xff,
+        }
+    }
+
+    fn write_port(&mut self, port: u16, value: u8) {
+        match port {
+            0x3d8 => self.mode_control = value,
+            0x3d9 => self.colour_select = value,
+            _ => (),
+        }
+    }
+}
+
+/// Writes numbered PPM snapshots, every `every` frames
+#[derive(Debug)]
+pub struct FrameDumper {
+    prefix: PathBuf,
+    every: u32,
+    frames: u32,
+}
+
+impl FrameDumper {
+    /// Files are written as `<prefix>_<frame>.ppm`
+    pub fn new<P: AsRef<Path>>(prefix: P, every: u32) -> Self {
+        Self {
+            prefix: prefix.as_ref().to_path_buf(),
+            every: every.max(1),
+            frames: 0,
+        }
+    }
+
+    /// Call once per emulated frame, returns the path if this frame got written
+    pub fn frame(&mut self, framebuffer: &Framebuffer) -> Result<Option<PathBuf>> {
+        let frame = self.frames;
+        self.frames += 1;
+
+        if !frame.is_multiple_of(self.every) {
+            return Ok(None);
+        }
+
+        let mut name = self.prefix.clone().into_os_string();
+        name.push(format!("_{:06}.ppm", frame));
+        let path = PathBuf::from(name);
+
+        framebuffer.write_ppm(&path)?;
+        Ok(Some(path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_text_screen() -> Result<()> {
+        let video_ram = VideoRam::new(TextAdapter::Cga).shared();
+        let mut memory = MemoryMap::new();
+        memory.map_device(video_ram.borrow().range(), Box::new(video_ram.clone()))?;
+
+        let line = b"C:\\>dir";
+        for (i, byte) in line.iter().enumerate() {
+            memory.write_bytes(CGA_TEXT_ADDRESS as u32 + i as u32 * 2, &[*byte, 0x07]);
+        }
+        // bright white on blue box drawing in the bottom right corner, written through the mirror at bc000
+        memory.write_bytes(0xbc000 + (24 * 80 + 79) * 2, &[0xdb, 0x1f]);
+
+        let screen = TextScreen::from_memory(&mut memory, TextAdapter::Cga);
+        assert_eq!(
+            screen,
+            TextScreen::from_buffer(video_ram.borrow().bytes(), TextAdapter::Cga)
+        );
+        assert_eq!(screen.row(0), "C:\\>dir");
+        assert_eq!(screen.row(1), "");
+        assert!(screen.to_text().ends_with(&format!("{}█", " ".repeat(79))));
+
+        let ansi = screen.to_ansi();
+        assert!(ansi.starts_with("\x1b[H\x1b[0;37;40mC:\\>dir"));
+        assert!(ansi.contains("\x1b[0;97;44m█\x1b[0m\n"));
+
+        // a short buffer fills the rest of the screen with blanks rather than panicking later
+        let short = TextScreen::from_buffer(&[b'A', 0x07], TextAdapter::Mda);
+        assert_eq!(short.row(0), "A");
+        assert_eq!(short.cell(24, 79).character, b' ');
+        assert_eq!(short.to_text().split('\n').count(), TEXT_ROWS);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cga_graphics() -> Result<()> {
+        let mut memory = MemoryMap::new();
+        let video_ram = VideoRam::new(TextAdapter::Cga);
+        memory.map_device(video_ram.range(), Box::new(video_ram))?;
+        // line 0 starts with pixels 3, 2, 1, 0 - line 1 (odd bank) starts with colour 1
+        memory.write_byte(CGA_TEXT_ADDRESS as u32, 0b11_10_01_00);
+        memory.write_byte((CGA_TEXT_ADDRESS + CGA_ODD_BANK) as u32, 0b01_00_00_00);
+
+        let mut cga = CgaRegisters::new();
+        assert_eq!(cga.decode(&mut memory), None);
+
+        // 320x200 graphics, palette 1 with intensity on a blue background
+        cga.write_port(0x3d8, 0b0000_1010);
+        cga.write_port(0x3d9, 0b0011_0001);
+        let frame = cga.decode(&mut memory).unwrap();
+
+        assert_eq!((frame.width(), frame.height()), (320, 200));
+        assert_eq!(frame.pixel(0, 0), CGA_RGB[15]);
+        assert_eq!(frame.pixel(1, 0), CGA_RGB[13]);
+        assert_eq!(frame.pixel(2, 0), CGA_RGB[11]);
+        assert_eq!(frame.pixel(3, 0), CGA_RGB[1]);
+        assert_eq!(frame.pixel(0, 1), CGA_RGB[11]);
+
+        // 640x200, white foreground
+        cga.write_port(0x3d8, 0b0001_1010);
+        cga.write_port(0x3d9, 0x0f);
+        let frame = cga.decode(&mut memory).unwrap();
+
+        assert_eq!(frame.width(), 640);
+        assert_eq!(frame.pixel(0, 0), CGA_RGB[15]);
+        assert_eq!(frame.pixel(3, 0), CGA_RGB[0]);
+        assert_eq!(frame.pixel(1, 1), CGA_RGB[15]);
+
+        let ppm = frame.to_ppm();
+        assert!(ppm.starts_with(b"P6\n640 200\n255\n"));
+        assert_eq!(ppm.len(), 15 + 640 * 200 * 3);
+
+        // a short buffer decodes as much as it has
+        let frame = decode_cga_graphics(&[0xff; 100], CgaGraphicsMode::High, 0x0f, 0);
+        assert_eq!(frame.pixel(639, 0), CGA_RGB[15]);
+        assert_eq!(frame.pixel(0, 199), CGA_RGB[0]);
+        Ok(())
+    }
+}